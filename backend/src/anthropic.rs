@@ -0,0 +1,1072 @@
+//! Translation between the OpenAI chat completions API and the Anthropic Messages API
+
+use std::collections::HashMap;
+
+use serde_json::{json, Value};
+
+use crate::chat::{now, text_of};
+use crate::sse;
+
+pub const API_VERSION: &str = "2023-06-01";
+
+/// Anthropic requires `max_tokens`, this is used when the request doesn't set it
+const DEFAULT_MAX_TOKENS: u64 = 4096;
+
+/// An Anthropic image block for an OpenAI `image_url`, which may be a data URL
+fn image_block(url: &str) -> Value {
+    if let Some((media_type, data)) = url
+        .strip_prefix("data:")
+        .and_then(|rest| rest.split_once(";base64,"))
+    {
+        json!({
+            "type": "image",
+            "source": { "type": "base64", "media_type": media_type, "data": data },
+        })
+    } else {
+        json!({
+            "type": "image",
+            "source": { "type": "url", "url": url },
+        })
+    }
+}
+
+fn content_blocks(content: &Value) -> Vec<Value> {
+    match content {
+        Value::String(s) if !s.is_empty() => vec![json!({ "type": "text", "text": s })],
+        Value::Array(parts) => parts
+            .iter()
+            .filter_map(|part| match part.get("type").and_then(|t| t.as_str()) {
+                Some("text") => part
+                    .get("text")
+                    .and_then(|t| t.as_str())
+                    .filter(|t| !t.is_empty())
+                    .map(|t| json!({ "type": "text", "text": t })),
+                Some("image_url") => part
+                    .get("image_url")
+                    .and_then(|i| i.get("url").or(Some(i)))
+                    .and_then(|u| u.as_str())
+                    .map(image_block),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Appends a message, merging it into the previous one if both have the same role since
+/// Anthropic expects user and assistant turns to alternate
+fn push_message(messages: &mut Vec<Value>, role: &str, blocks: Vec<Value>) {
+    if blocks.is_empty() {
+        return;
+    }
+    if let Some(last) = messages.last_mut() {
+        if last["role"] == role {
+            if let Some(content) = last["content"].as_array_mut() {
+                content.extend(blocks);
+                return;
+            }
+        }
+    }
+    messages.push(json!({ "role": role, "content": blocks }));
+}
+
+/// Converts an OpenAI chat completion request into an Anthropic Messages request
+pub fn messages_request(body: &HashMap<String, Value>) -> Value {
+    let mut system = Vec::new();
+    let mut messages = Vec::new();
+    let empty = Vec::new();
+    let openai_messages = body
+        .get("messages")
+        .and_then(|m| m.as_array())
+        .unwrap_or(&empty);
+
+    for message in openai_messages {
+        let content = message.get("content").unwrap_or(&Value::Null);
+        match message
+            .get("role")
+            .and_then(|r| r.as_str())
+            .unwrap_or("user")
+        {
+            "system" | "developer" => system.push(text_of(content)),
+            "assistant" => {
+                let mut blocks = content_blocks(content);
+                if let Some(calls) = message.get("tool_calls").and_then(|c| c.as_array()) {
+                    for call in calls {
+                        let function = &call["function"];
+                        let input = function["arguments"]
+                            .as_str()
+                            .and_then(|a| serde_json::from_str::<Value>(a).ok())
+                            .unwrap_or_else(|| json!({}));
+                        blocks.push(json!({
+                            "type": "tool_use",
+                            "id": call["id"],
+                            "name": function["name"],
+                            "input": input,
+                        }));
+                    }
+                }
+                push_message(&mut messages, "assistant", blocks);
+            }
+            "tool" | "function" => {
+                let tool_use_id = message
+                    .get("tool_call_id")
+                    .or(message.get("name"))
+                    .cloned()
+                    .unwrap_or(Value::Null);
+                let content = match content {
+                    Value::Array(_) => Value::Array(content_blocks(content)),
+                    _ => Value::String(text_of(content)),
+                };
+                push_message(
+                    &mut messages,
+                    "user",
+                    vec![json!({
+                        "type": "tool_result",
+                        "tool_use_id": tool_use_id,
+                        "content": content,
+                    })],
+                );
+            }
+            _ => push_message(&mut messages, "user", content_blocks(content)),
+        }
+    }
+
+    let max_tokens = body
+        .get("max_tokens")
+        .or(body.get("max_completion_tokens"))
+        .and_then(|m| m.as_u64())
+        .unwrap_or(DEFAULT_MAX_TOKENS);
+    let mut request = json!({
+        "model": body.get("model").cloned().unwrap_or(Value::Null),
+        "messages": messages,
+        "max_tokens": max_tokens,
+    });
+    if !system.is_empty() {
+        request["system"] = json!(system.join("\n\n"));
+    }
+    for key in ["top_p", "top_k", "stream"] {
+        if let Some(value) = body.get(key) {
+            request[key] = value.clone();
+        }
+    }
+    // OpenAI allows up to 2, Anthropic rejects anything above 1
+    if let Some(temperature) = body.get("temperature").and_then(|t| t.as_f64()) {
+        request["temperature"] = json!(temperature.clamp(0.0, 1.0));
+    }
+    match body.get("stop") {
+        Some(Value::String(stop)) => request["stop_sequences"] = json!([stop]),
+        Some(Value::Array(stop)) => request["stop_sequences"] = json!(stop),
+        _ => {}
+    }
+    if let Some(user) = body.get("user") {
+        request["metadata"] = json!({ "user_id": user });
+    }
+
+    if let Some(tools) = body.get("tools").and_then(|t| t.as_array()) {
+        let tools: Vec<Value> = tools
+            .iter()
+            .filter_map(|tool| tool.get("function"))
+            .map(|function| {
+                json!({
+                    "name": function["name"],
+                    "description": function.get("description").cloned().unwrap_or(json!("")),
+                    "input_schema": function
+                        .get("parameters")
+                        .cloned()
+                        .unwrap_or_else(|| json!({ "type": "object", "properties": {} })),
+                })
+            })
+            .collect();
+        if !tools.is_empty() {
+            request["tools"] = json!(tools);
+        }
+    }
+    let mut tool_choice = match body.get("tool_choice") {
+        Some(Value::String(choice)) if choice == "required" => Some(json!({ "type": "any" })),
+        Some(Value::String(choice)) if choice == "none" => Some(json!({ "type": "none" })),
+        Some(Value::String(_)) => Some(json!({ "type": "auto" })),
+        Some(Value::Object(choice)) => choice
+            .get("function")
+            .and_then(|f| f.get("name"))
+            .map(|name| json!({ "type": "tool", "name": name })),
+        _ => None,
+    };
+    if body.get("parallel_tool_calls") == Some(&Value::Bool(false)) {
+        let choice = tool_choice.get_or_insert_with(|| json!({ "type": "auto" }));
+        choice["disable_parallel_tool_use"] = json!(true);
+    }
+    if let (Some(choice), Some(_)) = (tool_choice, request.get("tools")) {
+        request["tool_choice"] = choice;
+    }
+    request
+}
+
+fn finish_reason(stop_reason: &str) -> &'static str {
+    match stop_reason {
+        "max_tokens" => "length",
+        "tool_use" => "tool_calls",
+        "refusal" => "content_filter",
+        _ => "stop",
+    }
+}
+
+/// OpenAI usage for the token counts Anthropic reports. Cached input is part of the
+/// prompt, as in OpenAI's accounting.
+fn usage(input_tokens: u64, output_tokens: u64, cached_tokens: u64) -> Value {
+    json!({
+        "prompt_tokens": input_tokens,
+        "completion_tokens": output_tokens,
+        "total_tokens": input_tokens + output_tokens,
+        "prompt_tokens_details": { "cached_tokens": cached_tokens },
+    })
+}
+
+/// Input, output and cache read tokens of an Anthropic `usage` object
+fn token_counts(usage: &Value) -> (u64, u64, u64) {
+    let count = |key: &str| usage.get(key).and_then(|t| t.as_u64()).unwrap_or(0);
+    let cached = count("cache_read_input_tokens");
+    (
+        count("input_tokens") + count("cache_creation_input_tokens") + cached,
+        count("output_tokens"),
+        cached,
+    )
+}
+
+/// Converts an Anthropic Messages response into an OpenAI chat completion
+pub fn chat_response(message: &Value) -> Value {
+    let mut text = String::new();
+    let mut tool_calls = Vec::new();
+    for block in message["content"].as_array().into_iter().flatten() {
+        match block["type"].as_str() {
+            Some("text") => text.push_str(block["text"].as_str().unwrap_or_default()),
+            Some("tool_use") => tool_calls.push(json!({
+                "id": block["id"],
+                "type": "function",
+                "function": {
+                    "name": block["name"],
+                    "arguments": block["input"].to_string(),
+                },
+            })),
+            _ => {}
+        }
+    }
+
+    let mut reply = json!({
+        "role": "assistant",
+        "content": if text.is_empty() && !tool_calls.is_empty() { Value::Null } else { json!(text) },
+    });
+    if !tool_calls.is_empty() {
+        reply["tool_calls"] = json!(tool_calls);
+    }
+    let (input_tokens, output_tokens, cached_tokens) = token_counts(&message["usage"]);
+    json!({
+        "id": message["id"],
+        "object": "chat.completion",
+        "created": now(),
+        "model": message["model"],
+        "choices": [{
+            "index": 0,
+            "message": reply,
+            "finish_reason": message["stop_reason"].as_str().map(finish_reason),
+        }],
+        "usage": usage(input_tokens, output_tokens, cached_tokens),
+    })
+}
+
+/// Converts an Anthropic error body into the OpenAI error format
+pub fn chat_error(body: &Value) -> Value {
+    json!({
+        "error": {
+            "message": body["error"]["message"],
+            "type": body["error"]["type"],
+            "code": Value::Null,
+        }
+    })
+}
+
+/// Translates an Anthropic Messages event stream into OpenAI chat completion chunks
+#[derive(Default)]
+pub struct ChatStreamTranslator {
+    id: Value,
+    model: Value,
+    created: u64,
+    input_tokens: u64,
+    output_tokens: u64,
+    cached_tokens: u64,
+    /// OpenAI tool call index of each Anthropic content block that is a tool use
+    tool_calls: HashMap<u64, usize>,
+}
+
+impl ChatStreamTranslator {
+    fn chunk(&self, delta: Value, finish_reason: Option<&str>) -> Value {
+        json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+        })
+    }
+
+    /// Translates one Anthropic event into zero or more OpenAI SSE frames
+    pub fn event(&mut self, event: &sse::Event) -> Vec<String> {
+        let Ok(data) = serde_json::from_str::<Value>(&event.data) else {
+            return Vec::new();
+        };
+        let frame = |value: Value| format!("data: {}\n\n", value);
+        match data["type"].as_str().unwrap_or_default() {
+            "message_start" => {
+                let message = &data["message"];
+                self.id = message["id"].clone();
+                self.model = message["model"].clone();
+                self.created = now();
+                (self.input_tokens, self.output_tokens, self.cached_tokens) =
+                    token_counts(&message["usage"]);
+                vec![frame(
+                    self.chunk(json!({ "role": "assistant", "content": "" }), None),
+                )]
+            }
+            "content_block_start" => {
+                let block = &data["content_block"];
+                if block["type"] != "tool_use" {
+                    return Vec::new();
+                }
+                let index = self.tool_calls.len();
+                self.tool_calls
+                    .insert(data["index"].as_u64().unwrap_or_default(), index);
+                vec![frame(self.chunk(
+                    json!({ "tool_calls": [{
+                        "index": index,
+                        "id": block["id"],
+                        "type": "function",
+                        "function": { "name": block["name"], "arguments": "" },
+                    }] }),
+                    None,
+                ))]
+            }
+            "content_block_delta" => {
+                let delta = &data["delta"];
+                match delta["type"].as_str() {
+                    Some("text_delta") => {
+                        vec![frame(self.chunk(json!({ "content": delta["text"] }), None))]
+                    }
+                    Some("input_json_delta") => {
+                        let block = data["index"].as_u64().unwrap_or_default();
+                        let Some(index) = self.tool_calls.get(&block) else {
+                            return Vec::new();
+                        };
+                        vec![frame(self.chunk(
+                            json!({ "tool_calls": [{
+                                "index": index,
+                                "function": { "arguments": delta["partial_json"] },
+                            }] }),
+                            None,
+                        ))]
+                    }
+                    _ => Vec::new(),
+                }
+            }
+            "message_delta" => {
+                if let Some(output_tokens) = data["usage"]["output_tokens"].as_u64() {
+                    self.output_tokens = output_tokens;
+                }
+                match data["delta"]["stop_reason"].as_str() {
+                    Some(reason) => vec![frame(self.chunk(json!({}), Some(finish_reason(reason))))],
+                    None => Vec::new(),
+                }
+            }
+            "message_stop" => vec![
+                frame(json!({
+                    "id": self.id,
+                    "object": "chat.completion.chunk",
+                    "created": self.created,
+                    "model": self.model,
+                    "choices": [],
+                    "usage": usage(self.input_tokens, self.output_tokens, self.cached_tokens),
+                })),
+                "data: [DONE]\n\n".to_string(),
+            ],
+            "error" => vec![frame(chat_error(&data))],
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// OpenAI message content for a list of Anthropic content blocks
+fn chat_content(blocks: &[Value]) -> Value {
+    let parts: Vec<Value> = blocks
+        .iter()
+        .filter_map(|block| match block["type"].as_str() {
+            Some("text") => Some(json!({ "type": "text", "text": block["text"] })),
+            Some("image") => {
+                let source = &block["source"];
+                let url = match source["type"].as_str() {
+                    Some("base64") => format!(
+                        "data:{};base64,{}",
+                        source["media_type"].as_str().unwrap_or_default(),
+                        source["data"].as_str().unwrap_or_default()
+                    ),
+                    _ => source["url"].as_str().unwrap_or_default().to_owned(),
+                };
+                Some(json!({ "type": "image_url", "image_url": { "url": url } }))
+            }
+            _ => None,
+        })
+        .collect();
+    // Plain text is understood by more upstreams than a list of parts
+    if parts.iter().all(|p| p["type"] == "text") {
+        json!(parts
+            .iter()
+            .filter_map(|p| p["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n"))
+    } else {
+        json!(parts)
+    }
+}
+
+/// Converts an Anthropic Messages request into an OpenAI chat completion request
+pub fn chat_request(body: &Value) -> HashMap<String, Value> {
+    let mut messages = Vec::new();
+    match &body["system"] {
+        Value::String(system) => messages.push(json!({ "role": "system", "content": system })),
+        Value::Array(blocks) => messages.push(json!({
+            "role": "system",
+            "content": chat_content(blocks),
+        })),
+        _ => {}
+    }
+
+    for message in body["messages"].as_array().into_iter().flatten() {
+        let role = message["role"].as_str().unwrap_or("user");
+        let blocks = match &message["content"] {
+            Value::String(text) => vec![json!({ "type": "text", "text": text })],
+            Value::Array(blocks) => blocks.clone(),
+            _ => Vec::new(),
+        };
+
+        if role == "assistant" {
+            let tool_calls: Vec<Value> = blocks
+                .iter()
+                .filter(|b| b["type"] == "tool_use")
+                .map(|b| {
+                    json!({
+                        "id": b["id"],
+                        "type": "function",
+                        "function": { "name": b["name"], "arguments": b["input"].to_string() },
+                    })
+                })
+                .collect();
+            let mut reply = json!({ "role": "assistant", "content": chat_content(&blocks) });
+            if !tool_calls.is_empty() {
+                reply["tool_calls"] = json!(tool_calls);
+            }
+            messages.push(reply);
+            continue;
+        }
+
+        // Tool results become tool messages, which OpenAI expects before anything else the
+        // user says
+        for result in blocks.iter().filter(|b| b["type"] == "tool_result") {
+            let content = match &result["content"] {
+                Value::Array(blocks) => chat_content(blocks),
+                Value::Null => json!(""),
+                content => content.clone(),
+            };
+            messages.push(json!({
+                "role": "tool",
+                "tool_call_id": result["tool_use_id"],
+                "content": content,
+            }));
+        }
+        let rest: Vec<Value> = blocks
+            .into_iter()
+            .filter(|b| b["type"] != "tool_result")
+            .collect();
+        if !rest.is_empty() {
+            messages.push(json!({ "role": "user", "content": chat_content(&rest) }));
+        }
+    }
+
+    let mut request = HashMap::from([
+        ("model".to_string(), body["model"].clone()),
+        ("messages".to_string(), json!(messages)),
+    ]);
+    for key in ["max_tokens", "temperature", "top_p", "top_k", "stream"] {
+        if let Some(value) = body.get(key) {
+            request.insert(key.to_string(), value.clone());
+        }
+    }
+    if let Some(stop) = body.get("stop_sequences") {
+        request.insert("stop".to_string(), stop.clone());
+    }
+    if let Some(user) = body["metadata"].get("user_id") {
+        request.insert("user".to_string(), user.clone());
+    }
+    if let Some(tools) = body["tools"].as_array() {
+        let tools: Vec<Value> = tools
+            .iter()
+            .map(|tool| {
+                json!({
+                    "type": "function",
+                    "function": {
+                        "name": tool["name"],
+                        "description": tool.get("description").cloned().unwrap_or(json!("")),
+                        "parameters": tool["input_schema"],
+                    },
+                })
+            })
+            .collect();
+        request.insert("tools".to_string(), json!(tools));
+    }
+    let choice = &body["tool_choice"];
+    let tool_choice = match choice["type"].as_str() {
+        Some("auto") => Some(json!("auto")),
+        Some("any") => Some(json!("required")),
+        Some("none") => Some(json!("none")),
+        Some("tool") => Some(json!({ "type": "function", "function": { "name": choice["name"] } })),
+        _ => None,
+    };
+    if let Some(tool_choice) = tool_choice {
+        request.insert("tool_choice".to_string(), tool_choice);
+    }
+    if choice["disable_parallel_tool_use"] == true {
+        request.insert("parallel_tool_calls".to_string(), json!(false));
+    }
+    request
+}
+
+fn stop_reason(finish_reason: &str) -> &'static str {
+    match finish_reason {
+        "length" => "max_tokens",
+        "tool_calls" | "function_call" => "tool_use",
+        "content_filter" => "refusal",
+        _ => "end_turn",
+    }
+}
+
+/// Anthropic usage for an OpenAI `usage` object
+fn messages_usage(usage: &Value) -> Value {
+    let cached = usage["prompt_tokens_details"]["cached_tokens"]
+        .as_u64()
+        .unwrap_or(0);
+    let prompt = usage["prompt_tokens"].as_u64().unwrap_or(0);
+    json!({
+        "input_tokens": prompt.saturating_sub(cached),
+        "output_tokens": usage["completion_tokens"].as_u64().unwrap_or(0),
+        "cache_read_input_tokens": cached,
+    })
+}
+
+/// Converts an OpenAI chat completion into an Anthropic Messages response
+pub fn messages_response(completion: &Value) -> Value {
+    let choice = &completion["choices"][0];
+    let message = &choice["message"];
+    let mut content = Vec::new();
+    if let Some(text) = message["content"].as_str().filter(|t| !t.is_empty()) {
+        content.push(json!({ "type": "text", "text": text }));
+    }
+    for call in message["tool_calls"].as_array().into_iter().flatten() {
+        let input = call["function"]["arguments"]
+            .as_str()
+            .and_then(|a| serde_json::from_str::<Value>(a).ok())
+            .unwrap_or_else(|| json!({}));
+        content.push(json!({
+            "type": "tool_use",
+            "id": call["id"],
+            "name": call["function"]["name"],
+            "input": input,
+        }));
+    }
+    json!({
+        "id": completion["id"],
+        "type": "message",
+        "role": "assistant",
+        "model": completion["model"],
+        "content": content,
+        "stop_reason": choice["finish_reason"].as_str().map(stop_reason),
+        "stop_sequence": Value::Null,
+        "usage": messages_usage(&completion["usage"]),
+    })
+}
+
+/// Converts an OpenAI error body into the Anthropic error format
+pub fn messages_error(body: &Value) -> Value {
+    json!({
+        "type": "error",
+        "error": {
+            "type": body["error"]["type"].as_str().unwrap_or("api_error"),
+            "message": body["error"]["message"],
+        }
+    })
+}
+
+/// The Anthropic content block a streamed OpenAI delta is currently writing to
+enum OpenBlock {
+    Text,
+    ToolUse,
+}
+
+/// Translates OpenAI chat completion chunks into an Anthropic Messages event stream
+#[derive(Default)]
+pub struct MessagesStreamTranslator {
+    /// Reported in `message_start` when the first chunk has no usage, OpenAI only sends
+    /// it at the end of the stream. The final counts follow in `message_delta`.
+    input_tokens: u64,
+    started: bool,
+    finished: bool,
+    blocks: usize,
+    open: Option<OpenBlock>,
+    /// Anthropic content block index of each OpenAI tool call index
+    tool_calls: HashMap<u64, usize>,
+    stop_reason: Option<&'static str>,
+    usage: Value,
+}
+
+fn event(name: &str, data: Value) -> String {
+    format!("event: {}\ndata: {}\n\n", name, data)
+}
+
+impl MessagesStreamTranslator {
+    /// A translator that reports `input_tokens`, an estimate of the prompt tokens, until
+    /// the upstream's count is known
+    pub fn new(input_tokens: u64) -> Self {
+        MessagesStreamTranslator {
+            input_tokens,
+            ..Default::default()
+        }
+    }
+
+    fn close_block(&mut self, frames: &mut Vec<String>) {
+        if self.open.take().is_some() {
+            frames.push(event(
+                "content_block_stop",
+                json!({ "type": "content_block_stop", "index": self.blocks - 1 }),
+            ));
+        }
+    }
+
+    fn open_block(&mut self, block: OpenBlock, content_block: Value, frames: &mut Vec<String>) {
+        self.close_block(frames);
+        frames.push(event(
+            "content_block_start",
+            json!({
+                "type": "content_block_start",
+                "index": self.blocks,
+                "content_block": content_block,
+            }),
+        ));
+        self.blocks += 1;
+        self.open = Some(block);
+    }
+
+    /// Translates one OpenAI SSE event into zero or more Anthropic SSE frames
+    pub fn event(&mut self, sse_event: &sse::Event) -> Vec<String> {
+        let mut frames = Vec::new();
+        if sse_event.data.trim() == "[DONE]" {
+            return self.finish();
+        }
+        let Ok(chunk) = serde_json::from_str::<Value>(&sse_event.data) else {
+            return frames;
+        };
+        if chunk.get("error").is_some() {
+            frames.push(event("error", messages_error(&chunk)));
+            return frames;
+        }
+
+        if chunk["usage"].is_object() {
+            self.usage = chunk["usage"].clone();
+        }
+        if !self.started {
+            self.started = true;
+            let mut usage = messages_usage(&self.usage);
+            if self.usage.is_null() {
+                usage["input_tokens"] = json!(self.input_tokens);
+            }
+            usage["output_tokens"] = json!(0);
+            frames.push(event(
+                "message_start",
+                json!({
+                    "type": "message_start",
+                    "message": {
+                        "id": chunk["id"],
+                        "type": "message",
+                        "role": "assistant",
+                        "model": chunk["model"],
+                        "content": [],
+                        "stop_reason": Value::Null,
+                        "stop_sequence": Value::Null,
+                        "usage": usage,
+                    },
+                }),
+            ));
+        }
+
+        for choice in chunk["choices"].as_array().into_iter().flatten() {
+            let delta = &choice["delta"];
+            if let Some(text) = delta["content"].as_str().filter(|t| !t.is_empty()) {
+                if !matches!(self.open, Some(OpenBlock::Text)) {
+                    self.open_block(
+                        OpenBlock::Text,
+                        json!({ "type": "text", "text": "" }),
+                        &mut frames,
+                    );
+                }
+                frames.push(event(
+                    "content_block_delta",
+                    json!({
+                        "type": "content_block_delta",
+                        "index": self.blocks - 1,
+                        "delta": { "type": "text_delta", "text": text },
+                    }),
+                ));
+            }
+            for call in delta["tool_calls"].as_array().into_iter().flatten() {
+                let index = call["index"].as_u64().unwrap_or_default();
+                if !self.tool_calls.contains_key(&index) {
+                    self.open_block(
+                        OpenBlock::ToolUse,
+                        json!({
+                            "type": "tool_use",
+                            "id": call["id"],
+                            "name": call["function"]["name"],
+                            "input": {},
+                        }),
+                        &mut frames,
+                    );
+                    self.tool_calls.insert(index, self.blocks - 1);
+                }
+                let Some(arguments) = call["function"]["arguments"]
+                    .as_str()
+                    .filter(|a| !a.is_empty())
+                else {
+                    continue;
+                };
+                frames.push(event(
+                    "content_block_delta",
+                    json!({
+                        "type": "content_block_delta",
+                        "index": self.tool_calls[&index],
+                        "delta": { "type": "input_json_delta", "partial_json": arguments },
+                    }),
+                ));
+            }
+            if let Some(reason) = choice["finish_reason"].as_str() {
+                self.stop_reason = Some(stop_reason(reason));
+            }
+        }
+        frames
+    }
+
+    /// Closes the message, once the OpenAI stream is over
+    pub fn finish(&mut self) -> Vec<String> {
+        let mut frames = Vec::new();
+        if !self.started || self.finished {
+            return frames;
+        }
+        self.finished = true;
+        self.close_block(&mut frames);
+        frames.push(event(
+            "message_delta",
+            json!({
+                "type": "message_delta",
+                "delta": {
+                    "stop_reason": self.stop_reason.unwrap_or("end_turn"),
+                    "stop_sequence": Value::Null,
+                },
+                "usage": messages_usage(&self.usage),
+            }),
+        ));
+        frames.push(event("message_stop", json!({ "type": "message_stop" })));
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{payloads, sse};
+
+    #[test]
+    fn messages_request_translates_text_and_tool_calls() {
+        let body: HashMap<String, Value> = serde_json::from_value(json!({
+            "model": "claude",
+            "messages": [
+                { "role": "system", "content": "Be brief" },
+                { "role": "user", "content": "Weather in Paris?" },
+                {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": { "name": "weather", "arguments": "{\"city\":\"Paris\"}" },
+                    }],
+                },
+                { "role": "tool", "tool_call_id": "call_1", "content": "Sunny" },
+            ],
+            "tools": [{
+                "type": "function",
+                "function": { "name": "weather", "parameters": { "type": "object" } },
+            }],
+            "tool_choice": "required",
+            "stop": "END",
+        }))
+        .unwrap();
+        let request = messages_request(&body);
+        assert_eq!(request["system"], "Be brief");
+        assert_eq!(request["max_tokens"], DEFAULT_MAX_TOKENS);
+        assert_eq!(
+            request["messages"],
+            json!([
+                { "role": "user", "content": [{ "type": "text", "text": "Weather in Paris?" }] },
+                { "role": "assistant", "content": [{
+                    "type": "tool_use", "id": "call_1", "name": "weather", "input": { "city": "Paris" },
+                }] },
+                { "role": "user", "content": [{
+                    "type": "tool_result", "tool_use_id": "call_1", "content": "Sunny",
+                }] },
+            ])
+        );
+        assert_eq!(
+            request["tools"][0]["input_schema"],
+            json!({ "type": "object" })
+        );
+        assert_eq!(request["tool_choice"], json!({ "type": "any" }));
+        assert_eq!(request["stop_sequences"], json!(["END"]));
+    }
+
+    #[test]
+    fn messages_request_clamps_temperature() {
+        let request = |temperature: Value| {
+            let body = HashMap::from([("temperature".to_string(), temperature)]);
+            messages_request(&body)["temperature"].clone()
+        };
+        assert_eq!(request(json!(1.5)), json!(1.0));
+        assert_eq!(request(json!(0.3)), json!(0.3));
+        assert_eq!(request(json!(-1)), json!(0.0));
+    }
+
+    #[test]
+    fn chat_response_translates_content_stop_reason_and_usage() {
+        let response = chat_response(&json!({
+            "id": "msg_1",
+            "model": "claude",
+            "content": [
+                { "type": "text", "text": "Let me check" },
+                { "type": "tool_use", "id": "toolu_1", "name": "weather", "input": { "city": "Paris" } },
+            ],
+            "stop_reason": "tool_use",
+            "usage": {
+                "input_tokens": 10,
+                "cache_creation_input_tokens": 5,
+                "cache_read_input_tokens": 20,
+                "output_tokens": 7,
+            },
+        }));
+        let choice = &response["choices"][0];
+        assert_eq!(choice["message"]["content"], "Let me check");
+        assert_eq!(
+            choice["message"]["tool_calls"][0]["function"],
+            json!({ "name": "weather", "arguments": "{\"city\":\"Paris\"}" })
+        );
+        assert_eq!(choice["finish_reason"], "tool_calls");
+        assert_eq!(response["usage"], usage(35, 7, 20));
+
+        let truncated = chat_response(&json!({ "content": [], "stop_reason": "max_tokens" }));
+        assert_eq!(truncated["choices"][0]["finish_reason"], "length");
+    }
+
+    #[test]
+    fn chat_request_translates_text_and_tool_results() {
+        let request = chat_request(&json!({
+            "model": "claude",
+            "system": "Be brief",
+            "max_tokens": 100,
+            "messages": [
+                { "role": "user", "content": "Weather in Paris?" },
+                { "role": "assistant", "content": [
+                    { "type": "tool_use", "id": "toolu_1", "name": "weather", "input": { "city": "Paris" } },
+                ] },
+                { "role": "user", "content": [
+                    { "type": "tool_result", "tool_use_id": "toolu_1", "content": "Sunny" },
+                    { "type": "text", "text": "Thanks" },
+                ] },
+            ],
+            "tool_choice": { "type": "tool", "name": "weather", "disable_parallel_tool_use": true },
+            "stop_sequences": ["END"],
+        }));
+        assert_eq!(
+            request["messages"],
+            json!([
+                { "role": "system", "content": "Be brief" },
+                { "role": "user", "content": "Weather in Paris?" },
+                { "role": "assistant", "content": "", "tool_calls": [{
+                    "id": "toolu_1",
+                    "type": "function",
+                    "function": { "name": "weather", "arguments": "{\"city\":\"Paris\"}" },
+                }] },
+                { "role": "tool", "tool_call_id": "toolu_1", "content": "Sunny" },
+                { "role": "user", "content": "Thanks" },
+            ])
+        );
+        assert_eq!(request["max_tokens"], 100);
+        assert_eq!(request["stop"], json!(["END"]));
+        assert_eq!(
+            request["tool_choice"],
+            json!({ "type": "function", "function": { "name": "weather" } })
+        );
+        assert_eq!(request["parallel_tool_calls"], false);
+    }
+
+    #[test]
+    fn messages_response_translates_content_stop_reason_and_usage() {
+        let response = messages_response(&json!({
+            "id": "chatcmpl-1",
+            "model": "gpt",
+            "choices": [{
+                "message": {
+                    "content": "Let me check",
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "function": { "name": "weather", "arguments": "{\"city\":\"Paris\"}" },
+                    }],
+                },
+                "finish_reason": "tool_calls",
+            }],
+            "usage": {
+                "prompt_tokens": 30,
+                "completion_tokens": 7,
+                "prompt_tokens_details": { "cached_tokens": 20 },
+            },
+        }));
+        assert_eq!(
+            response["content"],
+            json!([
+                { "type": "text", "text": "Let me check" },
+                { "type": "tool_use", "id": "call_1", "name": "weather", "input": { "city": "Paris" } },
+            ])
+        );
+        assert_eq!(response["stop_reason"], "tool_use");
+        assert_eq!(
+            response["usage"],
+            json!({ "input_tokens": 10, "output_tokens": 7, "cache_read_input_tokens": 20 })
+        );
+        let filtered =
+            messages_response(&json!({ "choices": [{ "finish_reason": "content_filter" }] }));
+        assert_eq!(filtered["stop_reason"], "refusal");
+    }
+
+    #[test]
+    fn chat_stream_translator_translates_text_tool_calls_and_usage() {
+        let events = [
+            json!({ "type": "message_start", "message": {
+                "id": "msg_1", "model": "claude", "usage": { "input_tokens": 12, "output_tokens": 1 },
+            } }),
+            json!({ "type": "content_block_start", "index": 0, "content_block": { "type": "text", "text": "" } }),
+            json!({ "type": "content_block_delta", "index": 0, "delta": { "type": "text_delta", "text": "Hi" } }),
+            json!({ "type": "content_block_stop", "index": 0 }),
+            json!({ "type": "content_block_start", "index": 1, "content_block": {
+                "type": "tool_use", "id": "toolu_1", "name": "weather", "input": {},
+            } }),
+            json!({ "type": "content_block_delta", "index": 1, "delta": {
+                "type": "input_json_delta", "partial_json": "{\"city\":",
+            } }),
+            json!({ "type": "message_delta", "delta": { "stop_reason": "tool_use" }, "usage": { "output_tokens": 9 } }),
+            json!({ "type": "message_stop" }),
+        ];
+        let mut translator = ChatStreamTranslator::default();
+        let frames: Vec<String> = events
+            .into_iter()
+            .flat_map(|data| translator.event(&sse(None, data)))
+            .collect();
+        assert_eq!(frames.last().unwrap(), "data: [DONE]\n\n");
+        let chunks = payloads(&frames);
+        let deltas: Vec<&Value> = chunks.iter().map(|c| &c["choices"][0]["delta"]).collect();
+        assert_eq!(deltas[0]["role"], "assistant");
+        assert_eq!(deltas[1]["content"], "Hi");
+        assert_eq!(deltas[2]["tool_calls"][0]["index"], 0);
+        assert_eq!(deltas[2]["tool_calls"][0]["function"]["name"], "weather");
+        assert_eq!(
+            deltas[3]["tool_calls"][0]["function"]["arguments"],
+            "{\"city\":"
+        );
+        assert_eq!(chunks[4]["choices"][0]["finish_reason"], "tool_calls");
+        assert_eq!(chunks[5]["usage"], usage(12, 9, 0));
+    }
+
+    #[test]
+    fn messages_stream_translator_translates_text_tool_calls_and_usage() {
+        let chunk = |delta: Value, finish_reason: Value| {
+            json!({
+                "id": "chatcmpl-1",
+                "model": "gpt",
+                "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+            })
+        };
+        let chunks = [
+            chunk(json!({ "role": "assistant", "content": "Hi" }), Value::Null),
+            chunk(
+                json!({ "tool_calls": [{
+                    "index": 0, "id": "call_1", "function": { "name": "weather", "arguments": "{}" },
+                }] }),
+                Value::Null,
+            ),
+            chunk(json!({}), json!("tool_calls")),
+            json!({ "id": "chatcmpl-1", "choices": [], "usage": { "prompt_tokens": 12, "completion_tokens": 9 } }),
+        ];
+        let mut translator = MessagesStreamTranslator::new(11);
+        let mut frames: Vec<String> = chunks
+            .into_iter()
+            .flat_map(|data| translator.event(&sse(None, data)))
+            .collect();
+        frames.extend(translator.event(&sse::Event {
+            event: None,
+            data: "[DONE]".to_string(),
+        }));
+        // Ending the stream twice doesn't close the message twice
+        assert!(translator.finish().is_empty());
+
+        let names: Vec<&str> = frames
+            .iter()
+            .filter_map(|f| f.lines().next()?.strip_prefix("event: "))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "message_start",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_stop",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_stop",
+                "message_delta",
+                "message_stop",
+            ]
+        );
+        let events = payloads(&frames);
+        assert_eq!(events[0]["message"]["usage"]["input_tokens"], 11);
+        assert_eq!(
+            events[2]["delta"],
+            json!({ "type": "text_delta", "text": "Hi" })
+        );
+        assert_eq!(events[4]["content_block"]["name"], "weather");
+        assert_eq!(events[5]["index"], 1);
+        assert_eq!(events[7]["delta"]["stop_reason"], "tool_use");
+        assert_eq!(
+            events[7]["usage"],
+            json!({ "input_tokens": 12, "output_tokens": 9, "cache_read_input_tokens": 0 })
+        );
+    }
+
+    #[test]
+    fn messages_stream_translator_reports_usage_of_the_first_chunk() {
+        let mut translator = MessagesStreamTranslator::new(11);
+        let frames = translator.event(&sse(
+            None,
+            json!({ "id": "chatcmpl-1", "choices": [], "usage": { "prompt_tokens": 12, "completion_tokens": 0 } }),
+        ));
+        assert_eq!(payloads(&frames)[0]["message"]["usage"]["input_tokens"], 12);
+    }
+}
@@ -0,0 +1,240 @@
+use log::warn;
+use rocket::http::Status;
+use serde::Serialize;
+
+use crate::config::{AppConfig, BudgetLimit, ProviderConfig};
+use crate::db;
+use crate::error::ApiError;
+use crate::pool;
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Period {
+    Daily,
+    Monthly,
+}
+
+impl Period {
+    /// The SQLite modifier for the start of the current period
+    fn start(self) -> &'static str {
+        match self {
+            Period::Daily => "start of day",
+            Period::Monthly => "start of month",
+        }
+    }
+}
+
+/// Use of a provider in the current period against its limits
+#[derive(Serialize)]
+pub struct PeriodStatus {
+    pub period: Period,
+    pub cost: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost_limit: Option<f64>,
+    pub tokens: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokens_limit: Option<u64>,
+    pub exceeded: bool,
+}
+
+#[derive(Serialize)]
+pub struct BudgetStatus {
+    pub provider: String,
+    pub periods: Vec<PeriodStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<String>,
+    pub exceeded: bool,
+}
+
+async fn period_status(provider_id: &str, period: Period, limit: &BudgetLimit) -> PeriodStatus {
+    let (cost, tokens) = db::spending(provider_id, period.start()).await;
+    PeriodStatus {
+        period,
+        cost,
+        cost_limit: limit.cost,
+        tokens,
+        tokens_limit: limit.tokens,
+        exceeded: limit.cost.is_some_and(|l| cost >= l)
+            || limit.tokens.is_some_and(|l| tokens >= l),
+    }
+}
+
+/// Where `provider` stands against its budget, `None` if it has none
+pub async fn status(provider: &ProviderConfig) -> Option<BudgetStatus> {
+    let budget = provider.budget.as_ref()?;
+    let mut periods = Vec::new();
+    for (period, limit) in [
+        (Period::Daily, &budget.daily),
+        (Period::Monthly, &budget.monthly),
+    ] {
+        if let Some(limit) = limit {
+            periods.push(period_status(&provider.id, period, limit).await);
+        }
+    }
+    Some(BudgetStatus {
+        provider: provider.id.clone(),
+        exceeded: periods.iter().any(|p| p.exceeded),
+        periods,
+        fallback: budget.fallback.clone(),
+    })
+}
+
+/// Replaces the providers in `chain` that used up their budget by their budget fallback,
+/// or drops them if they have none. Fails if no provider is left.
+pub async fn enforce(
+    config: &AppConfig,
+    chain: Vec<ProviderConfig>,
+) -> Result<Vec<ProviderConfig>, ApiError> {
+    let mut allowed: Vec<ProviderConfig> = Vec::new();
+    let mut seen: Vec<String> = Vec::new();
+    let mut exhausted = None;
+    let mut pending = chain;
+    pending.reverse();
+    while let Some(provider) = pending.pop() {
+        if seen.contains(&provider.id) {
+            continue;
+        }
+        seen.push(provider.id.clone());
+        let Some(status) = status(&provider).await.filter(|s| s.exceeded) else {
+            allowed.push(provider);
+            continue;
+        };
+        warn!("Provider '{}' has used up its budget", provider.id);
+        if let Some(fallback) = status
+            .fallback
+            .as_ref()
+            .and_then(|id| config.providers.iter().find(|p| &p.id == id))
+        {
+            // The fallback is tried in place of the provider, before the rest of the chain
+            let fallback = pool::expand(config, vec![fallback.clone()]);
+            pending.extend(fallback.into_iter().rev());
+        }
+        exhausted.get_or_insert(status);
+    }
+    if allowed.is_empty() {
+        let message = match exhausted {
+            Some(status) => format!(
+                "Provider '{}' has exceeded its budget, no provider is left to serve the request",
+                status.provider
+            ),
+            None => "No provider available".to_string(),
+        };
+        return Err(
+            ApiError::new(Status::TooManyRequests, "insufficient_quota", message)
+                .with_code("budget_exceeded"),
+        );
+    }
+    Ok(allowed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ids, provider, provider_with};
+    use rocket::tokio::sync::OnceCell;
+    use rusqlite::params;
+    use serde_json::json;
+
+    /// The database is global, so the tests share one and use their own provider ids
+    async fn database() {
+        static OPEN: OnceCell<()> = OnceCell::const_new();
+        OPEN.get_or_init(|| async { db::open(":memory:").await.unwrap() })
+            .await;
+    }
+
+    /// Logs a request of `provider_id` made at the current time shifted by `modifiers`
+    async fn spend(provider_id: &str, modifiers: [&str; 2], cost: f64, tokens: u64) {
+        database().await;
+        db::DB_CONNECTION
+            .lock()
+            .await
+            .as_ref()
+            .unwrap()
+            .execute(
+                "INSERT INTO requests (provider_id, request, request_time, model, cost, prompt_tokens, completion_tokens)
+                VALUES (?1, '{}', datetime('now', ?2, ?3), 'm', ?4, ?5, 0)",
+                params![provider_id, modifiers[0], modifiers[1], cost, tokens],
+            )
+            .unwrap();
+    }
+
+    const NOW: [&str; 2] = ["+0 seconds", "+0 seconds"];
+
+    fn budgeted(id: &str, budget: serde_json::Value) -> ProviderConfig {
+        provider_with(id, json!({ "budget": budget }))
+    }
+
+    async fn status_of(id: &str, budget: serde_json::Value) -> Option<BudgetStatus> {
+        status(&budgeted(id, budget)).await
+    }
+
+    #[rocket::async_test]
+    async fn daily_limit_counts_todays_requests() {
+        spend("daily-today", ["start of day", "+0 seconds"], 0.75, 0).await;
+        spend("daily-today", NOW, 0.5, 0).await;
+        spend("daily-yesterday", ["start of day", "-1 second"], 5.0, 0).await;
+        let budget = json!({ "daily": { "cost": 1.0 } });
+
+        let today = status_of("daily-today", budget.clone()).await.unwrap();
+        assert!(today.exceeded);
+        assert_eq!(today.periods[0].cost, 1.25);
+        let yesterday = status_of("daily-yesterday", budget).await.unwrap();
+        assert!(!yesterday.exceeded);
+        assert_eq!(yesterday.periods[0].cost, 0.0);
+    }
+
+    #[rocket::async_test]
+    async fn monthly_limit_counts_this_months_requests() {
+        spend("monthly-this", ["start of month", "+0 seconds"], 0.0, 60).await;
+        spend("monthly-this", NOW, 0.0, 40).await;
+        spend("monthly-last", ["start of month", "-1 second"], 0.0, 500).await;
+        let budget = json!({ "monthly": { "tokens": 100 } });
+
+        let this_month = status_of("monthly-this", budget.clone()).await.unwrap();
+        assert!(this_month.exceeded);
+        assert_eq!(this_month.periods[0].tokens, 100);
+        let last_month = status_of("monthly-last", budget).await.unwrap();
+        assert!(!last_month.exceeded);
+        assert!(status_of("monthly-none", json!(null)).await.is_none());
+    }
+
+    #[rocket::async_test]
+    async fn exceeded_providers_are_replaced_by_their_fallback() {
+        spend("fallback-spent", NOW, 2.0, 0).await;
+        spend("fallback-spent-too", NOW, 2.0, 0).await;
+        let limit = json!({ "cost": 1.0 });
+        let spent = budgeted(
+            "fallback-spent",
+            json!({ "daily": limit, "fallback": "fallback-spent-too" }),
+        );
+        let spent_too = budgeted(
+            "fallback-spent-too",
+            json!({ "daily": limit, "fallback": "fallback-spare" }),
+        );
+        let next = provider("fallback-next");
+        let config = AppConfig {
+            providers: vec![
+                spent.clone(),
+                spent_too.clone(),
+                provider("fallback-spare"),
+                next.clone(),
+            ],
+            ..Default::default()
+        };
+
+        // Fallbacks are followed until one has budget left, ahead of the rest of the chain
+        let chain = enforce(&config, vec![spent.clone(), next.clone()])
+            .await
+            .unwrap();
+        assert_eq!(ids(&chain), ["fallback-spare", "fallback-next"]);
+
+        // Without a fallback the provider is dropped, and with it the last one fails
+        let mut spent = spent;
+        spent.budget.as_mut().unwrap().fallback = None;
+        let chain = enforce(&config, vec![spent.clone(), next]).await.unwrap();
+        assert_eq!(ids(&chain), ["fallback-next"]);
+        let error = enforce(&config, vec![spent]).await.unwrap_err();
+        assert_eq!(error.status, Status::TooManyRequests);
+        assert_eq!(error.code, Some("budget_exceeded"));
+    }
+}
@@ -0,0 +1,229 @@
+//! OpenAI chat messages, as far as the request log and the API translations need to
+//! understand them
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+#[derive(Deserialize)]
+struct Message {
+    /// Missing or null for assistant messages that only call tools
+    #[serde(default)]
+    content: Option<Content>,
+    #[serde(default)]
+    tool_calls: Option<Vec<ToolCall>>,
+    /// The deprecated predecessor of `tool_calls`
+    #[serde(default)]
+    function_call: Option<FunctionCall>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Content {
+    Text(String),
+    Parts(Vec<Part>),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Part {
+    Text {
+        text: String,
+    },
+    /// Assistant messages can refuse instead of answering
+    Refusal {
+        refusal: String,
+    },
+    ImageUrl,
+    InputAudio,
+    File,
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct ToolCall {
+    /// Missing for custom tools, which take free-form input
+    #[serde(default)]
+    function: Option<FunctionCall>,
+}
+
+#[derive(Deserialize)]
+struct FunctionCall {
+    #[serde(default)]
+    name: String,
+    /// JSON encoded, though some clients send the object itself
+    #[serde(default)]
+    arguments: Value,
+}
+
+/// Content parts of a prompt that aren't text
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MediaParts {
+    pub images: u64,
+    pub audio: u64,
+    pub files: u64,
+}
+
+/// The prompt of a chat request as far as it can be tokenized, and what can't be
+#[derive(Default)]
+pub struct Prompt {
+    /// The text of every message, tool calls and tool results included, one per line
+    pub text: String,
+    pub media: MediaParts,
+}
+
+/// Unix time in seconds, as OpenAI responses report it in `created`
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Plain text of an OpenAI message content, which is either a string or a list of parts
+pub fn text_of(content: &Value) -> String {
+    match content {
+        Value::String(s) => s.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .filter_map(|p| p.get("text").and_then(|t| t.as_str()))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+/// Reads the `messages` of a chat request. Messages that don't parse are left out.
+pub fn prompt(messages: Option<&Value>) -> Prompt {
+    let mut prompt = Prompt::default();
+    let mut lines = Vec::new();
+    for message in messages.and_then(|m| m.as_array()).into_iter().flatten() {
+        let Ok(message) = Message::deserialize(message) else {
+            continue;
+        };
+        match message.content {
+            Some(Content::Text(text)) => lines.push(text),
+            Some(Content::Parts(parts)) => {
+                for part in parts {
+                    match part {
+                        Part::Text { text } => lines.push(text),
+                        Part::Refusal { refusal } => lines.push(refusal),
+                        Part::ImageUrl => prompt.media.images += 1,
+                        Part::InputAudio => prompt.media.audio += 1,
+                        Part::File => prompt.media.files += 1,
+                        Part::Other => {}
+                    }
+                }
+            }
+            None => {}
+        }
+        let calls = message
+            .tool_calls
+            .into_iter()
+            .flatten()
+            .filter_map(|call| call.function)
+            .chain(message.function_call);
+        for call in calls {
+            lines.push(call.name);
+            match call.arguments {
+                Value::String(arguments) => lines.push(arguments),
+                Value::Null => lines.push(String::new()),
+                arguments => lines.push(arguments.to_string()),
+            }
+        }
+    }
+    prompt.text = lines.join("\n");
+    prompt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reads_text_of_plain_messages() {
+        let messages = json!([
+            { "role": "system", "content": "Be brief" },
+            { "role": "user", "content": "Hello" },
+        ]);
+        let prompt = prompt(Some(&messages));
+        assert_eq!(prompt.text, "Be brief\nHello");
+        assert_eq!(prompt.media, MediaParts::default());
+    }
+
+    #[test]
+    fn counts_parts_that_arent_text() {
+        let messages = json!([{
+            "role": "user",
+            "content": [
+                { "type": "text", "text": "What is in these?" },
+                { "type": "image_url", "image_url": { "url": "https://example.com/a.png" } },
+                { "type": "image_url", "image_url": { "url": "data:image/png;base64,iVBO" } },
+                { "type": "input_audio", "input_audio": { "data": "", "format": "wav" } },
+                { "type": "file", "file": { "file_id": "file-1" } },
+            ],
+        }]);
+        let prompt = prompt(Some(&messages));
+        assert_eq!(prompt.text, "What is in these?");
+        assert_eq!(
+            prompt.media,
+            MediaParts {
+                images: 2,
+                audio: 1,
+                files: 1
+            }
+        );
+    }
+
+    #[test]
+    fn reads_tool_calls_and_results() {
+        let messages = json!([
+            { "role": "user", "content": "Weather in Paris?" },
+            {
+                "role": "assistant",
+                "content": null,
+                "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": { "name": "weather", "arguments": "{\"city\":\"Paris\"}" },
+                }],
+            },
+            { "role": "tool", "tool_call_id": "call_1", "content": [{ "type": "text", "text": "Sunny" }] },
+        ]);
+        let prompt = prompt(Some(&messages));
+        assert_eq!(
+            prompt.text,
+            "Weather in Paris?\nweather\n{\"city\":\"Paris\"}\nSunny"
+        );
+    }
+
+    #[test]
+    fn reads_tool_call_arguments_sent_as_objects() {
+        let messages = json!([{
+            "role": "assistant",
+            "tool_calls": [{
+                "id": "call_1",
+                "type": "function",
+                "function": { "name": "weather", "arguments": { "city": "Paris" } },
+            }],
+            "function_call": { "name": "time" },
+        }]);
+        assert_eq!(
+            prompt(Some(&messages)).text,
+            "weather\n{\"city\":\"Paris\"}\ntime\n"
+        );
+    }
+
+    #[test]
+    fn skips_messages_that_dont_parse() {
+        let messages = json!([
+            { "role": "user", "content": 42 },
+            { "role": "user", "content": "Hi" },
+        ]);
+        assert_eq!(prompt(Some(&messages)).text, "Hi");
+        assert_eq!(prompt(None).text, "");
+    }
+}
@@ -1,8 +1,16 @@
+use std::collections::HashSet;
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 use indexmap::IndexMap;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 
+/// Serializes writers so two saves never interleave on the temporary file
+static SAVE_LOCK: Mutex<()> = Mutex::new(());
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Preset {
     pub id: String,
@@ -10,15 +18,223 @@ pub struct Preset {
     pub overrides: IndexMap<String, serde_json::Value>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolStrategy {
+    #[default]
+    RoundRobin,
+    WeightedRandom,
+    LeastInFlight,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PoolMember {
+    pub provider: String,
+    /// Share of traffic for weighted strategies, 0 takes the member out of rotation
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PoolConfig {
+    #[serde(default)]
+    pub strategy: PoolStrategy,
+    pub members: Vec<PoolMember>,
+}
+
+fn default_max_attempts() -> u32 {
+    3
+}
+
+fn default_base_delay_ms() -> u64 {
+    500
+}
+
+fn default_max_delay_ms() -> u64 {
+    30_000
+}
+
+fn default_retry_on() -> Vec<u16> {
+    vec![429, 500, 502, 503, 504]
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RetryPolicy {
+    /// Attempts per request, including the first one
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every following one
+    #[serde(default = "default_base_delay_ms")]
+    pub base_delay_ms: u64,
+    /// Upper bound for any delay, including ones requested through `Retry-After`
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+    /// Upstream status codes that are retried. Connection errors are always retried.
+    #[serde(default = "default_retry_on")]
+    pub retry_on: Vec<u16>,
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `retry` (starting at 1), preferring the upstream's
+    /// `Retry-After` when it sent one
+    pub fn delay(&self, retry: u32, retry_after: Option<Duration>) -> Duration {
+        let backoff = Duration::from_millis(
+            self.base_delay_ms
+                .saturating_mul(1u64 << (retry.saturating_sub(1)).min(32)),
+        );
+        retry_after
+            .unwrap_or(backoff)
+            .min(Duration::from_millis(self.max_delay_ms))
+    }
+}
+
+/// Limits on what a provider may use over a period, either may be left out
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct BudgetLimit {
+    /// In US dollars, as computed from `pricing`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+    /// Prompt and completion tokens together
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<u64>,
+}
+
+/// Spending limits of a provider, counted from the requests it served. Days and months
+/// start at midnight UTC.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Budget {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daily: Option<BudgetLimit>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monthly: Option<BudgetLimit>,
+    /// Provider that takes the requests once a limit is reached, they are rejected if
+    /// there is none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<String>,
+}
+
+/// The API a provider speaks. Requests are always received in the OpenAI format and
+/// translated for the other kinds.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProviderKind {
+    #[default]
+    #[serde(rename = "openai")]
+    OpenAi,
+    Anthropic,
+    /// Ollama's native API, `api_url` is the server root such as `http://localhost:11434`
+    Ollama,
+}
+
+impl ProviderKind {
+    fn is_default(&self) -> bool {
+        *self == ProviderKind::default()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ProviderConfig {
     pub name: String,
     pub id: String,
+    #[serde(default, skip_serializing_if = "ProviderKind::is_default")]
+    pub kind: ProviderKind,
+    #[serde(default)]
     pub api_url: String,
+    #[serde(default)]
     pub api_key: String,
     pub presets: Vec<Preset>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub preset: Option<String>,
+    /// Makes this provider a pool that spreads requests over other providers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool: Option<PoolConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub budget: Option<Budget>,
+}
+
+fn default_models_cache_ttl_secs() -> u64 {
+    300
+}
+
+fn default_models_timeout_ms() -> u64 {
+    5_000
+}
+
+/// How `/api/v1/models` builds its listing
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ModelListing {
+    /// List the models of every provider, prefixed with the provider id, instead of only
+    /// those of the active provider
+    #[serde(default)]
+    pub aggregate: bool,
+    #[serde(default = "default_models_cache_ttl_secs")]
+    pub cache_ttl_secs: u64,
+    /// Time a provider gets to answer before it is left out of the listing
+    #[serde(default = "default_models_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl Default for ModelListing {
+    fn default() -> Self {
+        ModelListing {
+            aggregate: false,
+            cache_ttl_secs: default_models_cache_ttl_secs(),
+            timeout_ms: default_models_timeout_ms(),
+        }
+    }
+}
+
+/// Where requests for an aliased model name are sent
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ModelAlias {
+    pub provider: String,
+    pub model: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+}
+
+/// Prices of a model in US dollars per million tokens
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ModelPrice {
+    pub input: f64,
+    pub output: f64,
+    /// Price of prompt tokens read from the provider's cache, `input` if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cached_input: Option<f64>,
+}
+
+impl ModelPrice {
+    /// Cost of a request, `cached` is the part of `prompt` that was read from the cache
+    pub fn cost(&self, prompt: u64, completion: u64, cached: u64) -> f64 {
+        let cached = cached.min(prompt);
+        ((prompt - cached) as f64 * self.input
+            + cached as f64 * self.cached_input.unwrap_or(self.input)
+            + completion as f64 * self.output)
+            / 1_000_000.0
+    }
+}
+
+/// A tokenizer on disk, used to count tokens when the upstream neither reports them nor
+/// tokenizes
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LocalTokenizer {
+    /// A HuggingFace `tokenizer.json`
+    #[serde(rename = "huggingface")]
+    HuggingFace { path: PathBuf },
+    /// A `.tiktoken` file of base64 tokens and their ranks. `pattern` is the regex that
+    /// splits text before encoding, cl100k_base's if not set.
+    Tiktoken {
+        path: PathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pattern: Option<String>,
+    },
 }
 
 #[derive(Default, Clone, Serialize, Deserialize)]
@@ -26,13 +242,445 @@ pub struct AppConfig {
     pub providers: Vec<ProviderConfig>,
     pub provider: Option<String>,
     pub db_path: PathBuf,
+    /// Ordered provider ids to fail over to when a provider is unreachable or overloaded
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fallback: Vec<String>,
+    /// Model names clients may use, mapped to the provider and model that serve them
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub aliases: IndexMap<String, ModelAlias>,
+    #[serde(default)]
+    pub models: ModelListing,
+    /// Prices per provider id and model. The model `*` prices every model of the provider
+    /// that isn't listed.
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub pricing: IndexMap<String, IndexMap<String, ModelPrice>>,
+    /// Local tokenizers per model. A name ending in `*` matches every model starting with
+    /// the part before it, the first match is used.
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub tokenizers: IndexMap<String, LocalTokenizer>,
 }
 
 impl AppConfig {
+    /// Location of the config file, `<config dir>/aiswitch/config.json`
+    pub fn path() -> PathBuf {
+        dirs::config_dir()
+            .unwrap()
+            .join("aiswitch")
+            .join("config.json")
+    }
+
     pub fn load_from_file(path: impl AsRef<Path>) -> Result<Self, std::io::Error> {
         let file = std::fs::File::open(path)?;
         let reader = std::io::BufReader::new(file);
         let config = serde_json::from_reader(reader)?;
         Ok(config)
     }
+
+    /// Checks references between providers and presets that serde can't enforce
+    pub fn validate(&self) -> Result<(), String> {
+        let mut ids = HashSet::new();
+        for provider in &self.providers {
+            if !ids.insert(provider.id.as_str()) {
+                return Err(format!("duplicate provider id '{}'", provider.id));
+            }
+            if let Some(pool) = &provider.pool {
+                for member in &pool.members {
+                    match self.providers.iter().find(|p| p.id == member.provider) {
+                        None => {
+                            return Err(format!(
+                                "pool '{}' has unknown member '{}'",
+                                provider.id, member.provider
+                            ))
+                        }
+                        Some(p) if p.pool.is_some() => {
+                            return Err(format!(
+                                "pool '{}' can't contain pool '{}'",
+                                provider.id, member.provider
+                            ))
+                        }
+                        _ => {}
+                    }
+                }
+                if !pool.members.iter().any(|m| m.weight > 0) {
+                    return Err(format!("pool '{}' has no active members", provider.id));
+                }
+            }
+            if let Some(budget) = &provider.budget {
+                // Requests are logged under the member that served them, so a pool has no
+                // spending of its own
+                if provider.pool.is_some() {
+                    return Err(format!(
+                        "pool '{}' can't have a budget, set budgets on its members",
+                        provider.id
+                    ));
+                }
+                let limits = [&budget.daily, &budget.monthly];
+                if limits
+                    .iter()
+                    .filter_map(|l| l.as_ref()?.cost)
+                    .any(|c| !c.is_finite() || c < 0.0)
+                {
+                    return Err(format!("budget of '{}' must not be negative", provider.id));
+                }
+                if let Some(fallback) = &budget.fallback {
+                    if fallback == &provider.id || !self.providers.iter().any(|p| &p.id == fallback)
+                    {
+                        return Err(format!(
+                            "budget of '{}' falls back to invalid provider '{}'",
+                            provider.id, fallback
+                        ));
+                    }
+                }
+            }
+            if let Some(preset) = &provider.preset {
+                if !provider.presets.iter().any(|p| &p.id == preset) {
+                    return Err(format!(
+                        "provider '{}' selects unknown preset '{}'",
+                        provider.id, preset
+                    ));
+                }
+            }
+        }
+        if let Some(provider) = &self.provider {
+            if !ids.contains(provider.as_str()) {
+                return Err(format!("active provider '{}' does not exist", provider));
+            }
+        }
+        if let Some(provider) = self.fallback.iter().find(|id| !ids.contains(id.as_str())) {
+            return Err(format!("fallback provider '{}' does not exist", provider));
+        }
+        for (name, alias) in &self.aliases {
+            let Some(provider) = self.providers.iter().find(|p| p.id == alias.provider) else {
+                return Err(format!(
+                    "alias '{}' points to unknown provider '{}'",
+                    name, alias.provider
+                ));
+            };
+            if let Some(preset) = &alias.preset {
+                if !provider.presets.iter().any(|p| &p.id == preset) {
+                    return Err(format!("alias '{}' uses unknown preset '{}'", name, preset));
+                }
+            }
+        }
+        for (provider, models) in &self.pricing {
+            if !ids.contains(provider.as_str()) {
+                return Err(format!("pricing for unknown provider '{}'", provider));
+            }
+            for (model, price) in models {
+                let prices = [price.input, price.output, price.cached_input.unwrap_or(0.0)];
+                if prices.iter().any(|p| !p.is_finite() || *p < 0.0) {
+                    return Err(format!(
+                        "price of '{}' on '{}' must not be negative",
+                        model, provider
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The local tokenizer for `model`
+    pub fn tokenizer(&self, model: &str) -> Option<&LocalTokenizer> {
+        self.tokenizers.get(model).or_else(|| {
+            self.tokenizers.iter().find_map(|(name, tokenizer)| {
+                let prefix = name.strip_suffix('*')?;
+                model.starts_with(prefix).then_some(tokenizer)
+            })
+        })
+    }
+
+    /// Price of `model` on the provider with id `provider`
+    pub fn price(&self, provider: &str, model: &str) -> Option<&ModelPrice> {
+        let models = self.pricing.get(provider)?;
+        models.get(model).or_else(|| models.get("*"))
+    }
+
+    /// Providers to try for a request: `first`, then the providers that follow it in
+    /// `fallback`, or the whole list if `first` isn't part of it
+    pub fn failover_chain(&self, first: ProviderConfig) -> Vec<ProviderConfig> {
+        let rest = match self.fallback.iter().position(|id| *id == first.id) {
+            Some(index) => &self.fallback[index + 1..],
+            None => &self.fallback[..],
+        };
+        let mut chain = vec![first];
+        for id in rest {
+            if chain.iter().any(|p| &p.id == id) {
+                continue;
+            }
+            if let Some(provider) = self.providers.iter().find(|p| &p.id == id) {
+                chain.push(provider.clone());
+            }
+        }
+        chain
+    }
+
+    /// Writes the config to a temporary file next to `path` and renames it over the
+    /// original, so readers never observe a partially written file
+    pub fn save_to_file(&self, path: impl AsRef<Path>) -> Result<(), std::io::Error> {
+        let path = path.as_ref();
+        let _guard = SAVE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file_name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "config.json".to_string());
+        let tmp_path = path.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()));
+
+        let result = (|| {
+            let mut file = std::fs::File::create(&tmp_path)?;
+            serde_json::to_writer_pretty(&mut file, self)?;
+            file.write_all(b"\n")?;
+            file.sync_all()?;
+            std::fs::rename(&tmp_path, path)
+        })();
+        if result.is_err() {
+            let _ = std::fs::remove_file(&tmp_path);
+        }
+        result
+    }
+
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        self.save_to_file(Self::path())
+    }
+}
+
+fn file_stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// The config the routes share, changed through the API and reloaded from the file
+#[derive(Clone)]
+pub struct SharedConfig(Arc<rocket::tokio::sync::Mutex<AppConfig>>);
+
+impl SharedConfig {
+    pub fn new(config: AppConfig) -> Self {
+        SharedConfig(Arc::new(rocket::tokio::sync::Mutex::new(config)))
+    }
+
+    pub async fn lock(&self) -> rocket::tokio::sync::MutexGuard<'_, AppConfig> {
+        self.0.lock().await
+    }
+
+    /// A copy of the current config. Requests work on a snapshot so a reload doesn't
+    /// affect requests already in flight.
+    pub async fn snapshot(&self) -> AppConfig {
+        self.0.lock().await.clone()
+    }
+}
+
+/// Polls `path` and swaps a freshly loaded config into `config` whenever the file changes.
+/// Invalid files are logged and ignored, leaving the current config in place.
+pub fn watch_file(config: SharedConfig, path: PathBuf) {
+    rocket::tokio::spawn(async move {
+        let mut stamp = file_stamp(&path);
+        let mut interval = rocket::tokio::time::interval(Duration::from_secs(2));
+        loop {
+            interval.tick().await;
+            let current = file_stamp(&path);
+            if current.is_none() || current == stamp {
+                continue;
+            }
+            stamp = current;
+
+            let loaded = match AppConfig::load_from_file(&path) {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    warn!("Ignoring changes to {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            if let Err(e) = loaded.validate() {
+                warn!("Ignoring changes to {}: {}", path.display(), e);
+                continue;
+            }
+
+            let mut config = config.lock().await;
+            // Our own saves also touch the file, there is nothing to do for those
+            if serde_json::to_value(&*config).ok() == serde_json::to_value(&loaded).ok() {
+                continue;
+            }
+            if config.db_path != loaded.db_path {
+                warn!(
+                    "db_path changed in {}, restart to use the new database",
+                    path.display()
+                );
+            }
+            *config = loaded;
+            crate::tokenizer::forget_loaded();
+            info!("Reloaded configuration from {}", path.display());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ids, provider};
+    use serde_json::json;
+
+    fn config(providers: &[&str], fallback: &[&str]) -> AppConfig {
+        AppConfig {
+            providers: providers.iter().map(|id| provider(id)).collect(),
+            fallback: fallback.iter().map(|id| id.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn policy() -> RetryPolicy {
+        serde_json::from_value(json!({ "base_delay_ms": 100, "max_delay_ms": 1000 })).unwrap()
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_maximum() {
+        let policy = policy();
+        let delays: Vec<u64> = (1..=6)
+            .map(|retry| policy.delay(retry, None).as_millis() as u64)
+            .collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(policy.delay(100, None), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn retry_delay_prefers_retry_after_within_the_maximum() {
+        let policy = policy();
+        let retry_after = Some(Duration::from_millis(50));
+        assert_eq!(policy.delay(3, retry_after), Duration::from_millis(50));
+        let retry_after = Some(Duration::from_secs(60));
+        assert_eq!(policy.delay(1, retry_after), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn failover_chain_continues_after_the_first_provider() {
+        let config = config(&["a", "b", "c"], &["a", "b", "c"]);
+        assert_eq!(ids(&config.failover_chain(provider("b"))), ["b", "c"]);
+        assert_eq!(ids(&config.failover_chain(provider("c"))), ["c"]);
+    }
+
+    #[test]
+    fn failover_chain_uses_the_whole_list_for_other_providers() {
+        let config = config(&["a", "b", "c"], &["b", "c"]);
+        assert_eq!(ids(&config.failover_chain(provider("a"))), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn failover_chain_skips_unknown_and_repeated_providers() {
+        let config = config(&["a", "b"], &["b", "gone", "a", "b"]);
+        assert_eq!(ids(&config.failover_chain(provider("a"))), ["a", "b"]);
+        assert_eq!(ids(&config.failover_chain(provider("x"))), ["x", "b", "a"]);
+    }
+
+    #[test]
+    fn failover_chain_without_fallback_is_the_provider_alone() {
+        let config = config(&["a", "b"], &[]);
+        assert_eq!(ids(&config.failover_chain(provider("a"))), ["a"]);
+    }
+
+    #[test]
+    fn budgets_on_pools_are_rejected() {
+        let mut config = config(&["a", "b"], &[]);
+        config.providers[0].pool =
+            Some(serde_json::from_value(json!({ "members": [{ "provider": "b" }] })).unwrap());
+        assert!(config.validate().is_ok());
+        config.providers[0].budget =
+            Some(serde_json::from_value(json!({ "daily": { "cost": 1.0 } })).unwrap());
+        assert_eq!(
+            config.validate().unwrap_err(),
+            "pool 'a' can't have a budget, set budgets on its members"
+        );
+        config.providers[0].budget = None;
+        config.providers[1].budget =
+            Some(serde_json::from_value(json!({ "daily": { "cost": 1.0 } })).unwrap());
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn save_to_file_round_trips_and_replaces_the_file() {
+        let dir = std::env::temp_dir().join(format!("aiswitch-save-{}", std::process::id()));
+        let path = dir.join("config.json");
+        let mut config = config(&["a", "b"], &["b"]);
+        config.provider = Some("a".to_string());
+        config.save_to_file(&path).unwrap();
+        let loaded = AppConfig::load_from_file(&path).unwrap();
+        assert_eq!(ids(&loaded.providers), ["a", "b"]);
+        assert_eq!(loaded.provider.as_deref(), Some("a"));
+        assert_eq!(loaded.fallback, ["b"]);
+
+        config.providers.pop();
+        config.fallback.clear();
+        config.save_to_file(&path).unwrap();
+        let loaded = AppConfig::load_from_file(&path).unwrap();
+        assert_eq!(ids(&loaded.providers), ["a"]);
+        assert!(loaded.fallback.is_empty());
+        let files: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(files, ["config.json"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn model_prices_are_per_million_tokens() {
+        let price = ModelPrice {
+            input: 3.0,
+            output: 15.0,
+            cached_input: None,
+        };
+        assert_eq!(price.cost(1_000_000, 0, 0), 3.0);
+        assert_eq!(price.cost(0, 1_000_000, 0), 15.0);
+        assert_eq!(price.cost(2_000, 1_000, 0), 0.021);
+        assert_eq!(price.cost(0, 0, 0), 0.0);
+    }
+
+    #[test]
+    fn cached_prompt_tokens_use_their_own_price() {
+        let price = ModelPrice {
+            input: 3.0,
+            output: 15.0,
+            cached_input: Some(0.3),
+        };
+        assert_eq!(price.cost(1_000_000, 0, 500_000), 1.65);
+        // More cached tokens than prompt tokens are counted as the whole prompt
+        assert_eq!(price.cost(1_000_000, 0, 2_000_000), 0.3);
+    }
+
+    #[test]
+    fn prices_fall_back_to_the_provider_wildcard() {
+        let mut config = config(&["a", "b"], &[]);
+        config.pricing = serde_json::from_value(json!({ "a": {
+            "gpt": { "input": 1.0, "output": 2.0 },
+            "*": { "input": 5.0, "output": 6.0 },
+        } }))
+        .unwrap();
+        assert_eq!(config.price("a", "gpt").unwrap().input, 1.0);
+        assert_eq!(config.price("a", "llama").unwrap().input, 5.0);
+        assert!(config.price("b", "gpt").is_none());
+    }
+
+    #[test]
+    fn tokenizers_match_exact_names_before_prefixes() {
+        let tokenizer = |path: &str| LocalTokenizer::HuggingFace { path: path.into() };
+        let config = AppConfig {
+            tokenizers: IndexMap::from([
+                ("llama-3*".to_string(), tokenizer("llama-3.json")),
+                ("llama*".to_string(), tokenizer("llama.json")),
+                ("llama-2-7b".to_string(), tokenizer("llama-2-7b.json")),
+            ]),
+            ..Default::default()
+        };
+        let path = |model: &str| match config.tokenizer(model) {
+            Some(LocalTokenizer::HuggingFace { path }) => path.to_str(),
+            _ => None,
+        };
+        assert_eq!(path("llama-2-7b"), Some("llama-2-7b.json"));
+        // The first prefix that matches is used
+        assert_eq!(path("llama-3-8b"), Some("llama-3.json"));
+        assert_eq!(path("llama-2-13b"), Some("llama.json"));
+        assert_eq!(path("llama"), Some("llama.json"));
+        assert_eq!(path("gpt-4o"), None);
+    }
 }
@@ -0,0 +1,530 @@
+use std::path::Path;
+use std::sync::{Arc, LazyLock};
+
+use rocket::tokio::sync::Mutex;
+use rusqlite::{params, Connection};
+
+use crate::chat::MediaParts;
+use crate::config::{AppConfig, ModelPrice};
+use crate::proxy::RequestKind;
+use crate::tokenizer::TokenMethod;
+
+/// How a request ended, stored in the `outcome` column. Rows without an outcome are still
+/// in progress.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Success,
+    /// The upstream answered with an error status
+    UpstreamError,
+    /// The upstream couldn't be reached or the response broke off
+    NetworkError,
+    /// The client went away before the response was complete
+    Cancelled,
+}
+
+impl Outcome {
+    pub const ALL: [Outcome; 4] = [
+        Outcome::Success,
+        Outcome::UpstreamError,
+        Outcome::NetworkError,
+        Outcome::Cancelled,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::UpstreamError => "upstream_error",
+            Outcome::NetworkError => "network_error",
+            Outcome::Cancelled => "cancelled",
+        }
+    }
+}
+
+pub static DB_CONNECTION: LazyLock<Arc<Mutex<Option<Connection>>>> =
+    LazyLock::new(|| Arc::new(Mutex::new(None)));
+
+pub async fn open(db_path: impl AsRef<Path>) -> Result<(), String> {
+    let mut conn = Connection::open(db_path).map_err(|e| e.to_string())?;
+    migrate(&mut conn)?;
+    DB_CONNECTION.lock().await.replace(conn);
+    Ok(())
+}
+
+/// A step that upgrades the schema by one version
+type Migration = fn(&Connection) -> rusqlite::Result<()>;
+
+/// Every schema change, in order. The version of a database, stored in its `user_version`,
+/// is the number of migrations applied to it. New changes are appended here, the existing
+/// ones must stay as they are.
+const MIGRATIONS: &[Migration] = &[
+    |conn| {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS requests (
+                id INTEGER PRIMARY KEY,
+                timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                provider_id TEXT NOT NULL,
+                chat BOOLEAN DEFAULT FALSE,
+                request TEXT NOT NULL,
+                response TEXT,
+                request_time TIMESTAMP NOT NULL,
+                response_time TIMESTAMP,
+                prompt_tokens INTEGER,
+                completion_tokens INTEGER,
+                model TEXT NOT NULL,
+                SPEED INTEGER
+            )",
+        )
+    },
+    |conn| {
+        add_column(
+            conn,
+            "request_type",
+            "TEXT",
+            "UPDATE requests SET request_type = CASE WHEN chat THEN 'chat' ELSE 'completion' END",
+        )
+    },
+    |conn| add_column(conn, "status", "INTEGER", ""),
+    // Older rows only have the response to go by, failed requests stored an OpenAI error
+    // there and a missing upstream shows up as a 502 "upstream_error"
+    |conn| {
+        add_column(
+            conn,
+            "outcome",
+            "TEXT",
+            "UPDATE requests SET outcome = CASE
+                WHEN response_time IS NULL THEN NULL
+                WHEN status BETWEEN 200 AND 299 THEN 'success'
+                WHEN status IS NOT NULL THEN 'upstream_error'
+                WHEN response LIKE '{\"error\"%\"type\":\"upstream_error\"%' THEN 'network_error'
+                WHEN response LIKE '{\"error\"%' THEN 'upstream_error'
+                ELSE 'success'
+            END",
+        )
+    },
+    |conn| add_column(conn, "error", "TEXT", ""),
+    // Full-text index over the bodies for the log search, kept in sync by the triggers
+    |conn| {
+        conn.execute_batch(
+            "CREATE VIRTUAL TABLE requests_fts USING fts5(
+                request, response, content = 'requests', content_rowid = 'id'
+            );
+            CREATE TRIGGER requests_fts_insert AFTER INSERT ON requests BEGIN
+                INSERT INTO requests_fts (rowid, request, response)
+                    VALUES (new.id, new.request, new.response);
+            END;
+            CREATE TRIGGER requests_fts_delete AFTER DELETE ON requests BEGIN
+                INSERT INTO requests_fts (requests_fts, rowid, request, response)
+                    VALUES ('delete', old.id, old.request, old.response);
+            END;
+            CREATE TRIGGER requests_fts_update AFTER UPDATE OF request, response ON requests BEGIN
+                INSERT INTO requests_fts (requests_fts, rowid, request, response)
+                    VALUES ('delete', old.id, old.request, old.response);
+                INSERT INTO requests_fts (rowid, request, response)
+                    VALUES (new.id, new.request, new.response);
+            END;
+            INSERT INTO requests_fts (requests_fts) VALUES ('rebuild');",
+        )
+    },
+    |conn| add_column(conn, "cached_tokens", "INTEGER", ""),
+    // In US dollars, NULL when the model has no price
+    |conn| add_column(conn, "cost", "REAL", ""),
+    // How the token counts were obtained, unknown for older rows
+    |conn| add_column(conn, "prompt_tokens_method", "TEXT", ""),
+    |conn| add_column(conn, "completion_tokens_method", "TEXT", ""),
+    // Non-text parts of chat prompts, which the token counts of a local tokenizer miss
+    |conn| {
+        add_column(conn, "image_parts", "INTEGER", "")?;
+        add_column(conn, "audio_parts", "INTEGER", "")?;
+        add_column(conn, "file_parts", "INTEGER", "")
+    },
+    // For the spending of a provider in the current budget period
+    |conn| {
+        conn.execute_batch(
+            "CREATE INDEX IF NOT EXISTS requests_provider_time ON requests (provider_id, request_time)",
+        )
+    },
+];
+
+/// Brings the database up to the latest version. Each migration runs in its own
+/// transaction together with the version bump, so an interrupted upgrade resumes where
+/// it stopped.
+fn migrate(conn: &mut Connection) -> Result<(), String> {
+    let version: usize = conn
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .map_err(|e| e.to_string())?;
+    if version > MIGRATIONS.len() {
+        return Err(format!(
+            "The database is at schema version {} but this build only knows versions up to {}, it was written by a newer release",
+            version,
+            MIGRATIONS.len()
+        ));
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let apply = |conn: &mut Connection| {
+            let tx = conn.transaction()?;
+            migration(&tx)?;
+            tx.pragma_update(None, "user_version", i + 1)?;
+            tx.commit()
+        };
+        apply(conn).map_err(|e| format!("Migration to schema version {} failed: {}", i + 1, e))?;
+    }
+    Ok(())
+}
+
+fn has_column(conn: &Connection, column: &str) -> rusqlite::Result<bool> {
+    conn.prepare("SELECT 1 FROM pragma_table_info('requests') WHERE name = ?1")?
+        .exists([column])
+}
+
+/// Adds `column` and fills it in for the existing rows with `backfill`, if it isn't empty.
+/// Databases from before the versioning may have the column already, then nothing is done.
+fn add_column(
+    conn: &Connection,
+    column: &str,
+    definition: &str,
+    backfill: &str,
+) -> rusqlite::Result<()> {
+    if has_column(conn, column)? {
+        return Ok(());
+    }
+    conn.execute_batch(&format!(
+        "ALTER TABLE requests ADD COLUMN {} {}",
+        column, definition
+    ))?;
+    conn.execute_batch(backfill)
+}
+
+/// Inserts a new request row and returns its id
+pub async fn insert_request(
+    provider_id: &str,
+    kind: RequestKind,
+    request: &str,
+    model: &str,
+) -> i64 {
+    let db_lock = DB_CONNECTION.lock().await;
+    let db = db_lock.as_ref().unwrap();
+    db
+        .execute(
+            "INSERT INTO requests (provider_id, chat, request_type, request, request_time, model) VALUES (?1, ?2, ?3, ?4, CURRENT_TIMESTAMP, ?5)",
+            params![provider_id, kind == RequestKind::Chat, kind.name(), request, model],
+        )
+        .unwrap();
+    // As we have the connection locked, it is guranteed that this is the id of the request we just inserted
+    db.last_insert_rowid()
+}
+
+/// Tokens a request used, as far as they are known
+#[derive(Default, Clone, Copy)]
+pub struct Usage {
+    pub prompt_tokens: Option<u64>,
+    pub completion_tokens: Option<u64>,
+    /// Prompt tokens read from the provider's cache, included in `prompt_tokens`
+    pub cached_tokens: Option<u64>,
+    pub prompt_method: Option<TokenMethod>,
+    pub completion_method: Option<TokenMethod>,
+    /// Parts of a chat prompt that aren't text
+    pub media: Option<MediaParts>,
+}
+
+impl Usage {
+    /// Cost of the request at `price`, if any tokens are known
+    fn cost(&self, price: &ModelPrice) -> Option<f64> {
+        if self.prompt_tokens.is_none() && self.completion_tokens.is_none() {
+            return None;
+        }
+        Some(price.cost(
+            self.prompt_tokens.unwrap_or(0),
+            self.completion_tokens.unwrap_or(0),
+            self.cached_tokens.unwrap_or(0),
+        ))
+    }
+}
+
+/// Stores the response of a request. `status` is the HTTP status of the upstream, if it
+/// could be reached, and decides the outcome unless `failure` gives the outcome and error
+/// of a response that broke off or a client that went away. `response` must be valid
+/// JSON, for failed requests its OpenAI error message is stored as the error. The cost is
+/// computed from `usage` at `price`, the price of the model on the provider that served it.
+pub async fn finish_request(
+    id: i64,
+    status: Option<u16>,
+    failure: Option<(Outcome, &str)>,
+    response: &str,
+    usage: Usage,
+    speed: Option<i64>,
+    price: Option<&ModelPrice>,
+) {
+    let (outcome, error) = match (failure, status) {
+        (Some((outcome, error)), _) => (outcome, Some(error.to_owned())),
+        (None, Some(200..=299)) => (Outcome::Success, None),
+        (None, _) => {
+            let outcome = match status {
+                Some(_) => Outcome::UpstreamError,
+                None => Outcome::NetworkError,
+            };
+            let error = serde_json::from_str::<serde_json::Value>(response)
+                .ok()
+                .and_then(|r| r["error"]["message"].as_str().map(|m| m.to_owned()));
+            (outcome, error)
+        }
+    };
+    let cost = price.and_then(|p| usage.cost(p));
+    let media = usage.media;
+    DB_CONNECTION.lock().await.as_ref().unwrap()
+        .execute(
+            "UPDATE requests SET status = ?2, outcome = ?3, error = ?4, response = ?5, response_time = CURRENT_TIMESTAMP, prompt_tokens = ?6, completion_tokens = ?7, cached_tokens = ?8, speed = ?9, cost = ?10, prompt_tokens_method = ?11, completion_tokens_method = ?12, image_parts = ?13, audio_parts = ?14, file_parts = ?15 WHERE id = ?1",
+            params![id, status, outcome.as_str(), error, response, usage.prompt_tokens, usage.completion_tokens, usage.cached_tokens, speed, cost, usage.prompt_method.map(TokenMethod::as_str), usage.completion_method.map(TokenMethod::as_str), media.map(|m| m.images), media.map(|m| m.audio), media.map(|m| m.files)],
+        )
+        .unwrap();
+}
+
+/// Cost and tokens of the requests `provider_id` served since `start`, an SQLite time
+/// modifier such as `start of month` applied to the current time
+pub async fn spending(provider_id: &str, start: &str) -> (f64, u64) {
+    DB_CONNECTION
+        .lock()
+        .await
+        .as_ref()
+        .unwrap()
+        .query_row(
+            "SELECT COALESCE(SUM(cost), 0), COALESCE(SUM(COALESCE(prompt_tokens, 0) + COALESCE(completion_tokens, 0)), 0) FROM requests WHERE provider_id = ?1 AND request_time >= datetime('now', ?2)",
+            params![provider_id, start],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap()
+}
+
+/// Computes the cost of every finished request again with the current prices, for
+/// example after they changed. Returns the number of rows whose cost changed.
+pub async fn recompute_costs(config: &AppConfig) -> rusqlite::Result<usize> {
+    recompute_costs_in(DB_CONNECTION.lock().await.as_mut().unwrap(), config)
+}
+
+fn recompute_costs_in(conn: &mut Connection, config: &AppConfig) -> rusqlite::Result<usize> {
+    let tx = conn.transaction()?;
+    let mut changed = 0;
+    {
+        let mut select = tx.prepare(
+            "SELECT id, provider_id, model, prompt_tokens, completion_tokens, cached_tokens, cost FROM requests WHERE response_time IS NOT NULL",
+        )?;
+        let mut update = tx.prepare("UPDATE requests SET cost = ?2 WHERE id = ?1")?;
+        let mut rows = select.query([])?;
+        while let Some(row) = rows.next()? {
+            let provider_id: String = row.get(1)?;
+            let model: String = row.get(2)?;
+            let usage = Usage {
+                prompt_tokens: row.get(3)?,
+                completion_tokens: row.get(4)?,
+                cached_tokens: row.get(5)?,
+                ..Usage::default()
+            };
+            let old: Option<f64> = row.get(6)?;
+            let cost = config
+                .price(&provider_id, &model)
+                .and_then(|p| usage.cost(p));
+            if cost != old {
+                update.execute(params![row.get::<_, i64>(0)?, cost])?;
+                changed += 1;
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The schema of databases created before migrations existed
+    const V0_SCHEMA: &str = "CREATE TABLE requests (
+        id INTEGER PRIMARY KEY,
+        timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        provider_id TEXT NOT NULL,
+        chat BOOLEAN DEFAULT FALSE,
+        request TEXT NOT NULL,
+        response TEXT,
+        request_time TIMESTAMP NOT NULL,
+        response_time TIMESTAMP,
+        prompt_tokens INTEGER,
+        completion_tokens INTEGER,
+        model TEXT NOT NULL,
+        SPEED INTEGER
+    )";
+
+    fn version(conn: &Connection) -> usize {
+        conn.pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap()
+    }
+
+    fn columns(conn: &Connection) -> Vec<String> {
+        conn.prepare("SELECT name FROM pragma_table_info('requests')")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    fn v0_database() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(V0_SCHEMA).unwrap();
+        conn.execute_batch(
+            r#"INSERT INTO requests (id, provider_id, chat, request, response, request_time, response_time, model) VALUES
+                (1, 'a', TRUE, '{}', '{"choices":[]}', '2024-01-01 00:00:00', '2024-01-01 00:00:01', 'm'),
+                (2, 'a', FALSE, '{}', '{"error":{"message":"Invalid key","type":"authentication_error"}}', '2024-01-01 00:00:00', '2024-01-01 00:00:01', 'm'),
+                (3, 'b', TRUE, '{}', '{"error":{"code":null,"message":"Connection refused","type":"upstream_error"}}', '2024-01-01 00:00:00', '2024-01-01 00:00:01', 'm'),
+                (4, 'b', FALSE, '{}', NULL, '2024-01-01 00:00:00', NULL, 'm')"#,
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn creates_a_new_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(version(&conn), MIGRATIONS.len());
+        let columns = columns(&conn);
+        for column in ["request_type", "status", "outcome", "error"] {
+            assert!(columns.iter().any(|c| c == column), "missing {}", column);
+        }
+    }
+
+    #[test]
+    fn upgrades_a_v0_database() {
+        let mut conn = v0_database();
+        assert_eq!(version(&conn), 0);
+        migrate(&mut conn).unwrap();
+        assert_eq!(version(&conn), MIGRATIONS.len());
+
+        let mut new_database = Connection::open_in_memory().unwrap();
+        migrate(&mut new_database).unwrap();
+        assert_eq!(columns(&conn), columns(&new_database));
+
+        let rows: Vec<(i64, String, Option<String>)> = conn
+            .prepare("SELECT id, request_type, outcome FROM requests ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            [
+                (1, "chat".to_string(), Some("success".to_string())),
+                (
+                    2,
+                    "completion".to_string(),
+                    Some("upstream_error".to_string())
+                ),
+                (3, "chat".to_string(), Some("network_error".to_string())),
+                (4, "completion".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn indexes_existing_and_new_rows() {
+        let mut conn = v0_database();
+        migrate(&mut conn).unwrap();
+        let search = |conn: &Connection, query: &str| -> Vec<i64> {
+            conn.prepare(
+                "SELECT rowid FROM requests_fts WHERE requests_fts MATCH ?1 ORDER BY rowid",
+            )
+            .unwrap()
+            .query_map([query], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+        };
+        assert_eq!(search(&conn, "\"Invalid key\""), [2]);
+
+        conn.execute_batch(
+            r#"UPDATE requests SET response = '{"choices":["found later"]}' WHERE id = 4;
+            DELETE FROM requests WHERE id = 2"#,
+        )
+        .unwrap();
+        assert_eq!(search(&conn, "later"), [4]);
+        assert!(search(&conn, "Invalid").is_empty());
+    }
+
+    #[test]
+    fn migrating_twice_changes_nothing() {
+        let mut conn = v0_database();
+        migrate(&mut conn).unwrap();
+        let before = columns(&conn);
+        migrate(&mut conn).unwrap();
+        assert_eq!(version(&conn), MIGRATIONS.len());
+        assert_eq!(columns(&conn), before);
+    }
+
+    #[test]
+    fn refuses_a_database_from_a_newer_build() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(&format!("PRAGMA user_version = {}", MIGRATIONS.len() + 1))
+            .unwrap();
+        let error = migrate(&mut conn).unwrap_err();
+        assert!(error.contains("newer release"), "{}", error);
+    }
+
+    #[test]
+    fn resumes_a_partial_upgrade() {
+        let mut conn = v0_database();
+        conn.execute_batch("PRAGMA user_version = 1").unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(version(&conn), MIGRATIONS.len());
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM requests", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 4);
+    }
+
+    #[test]
+    fn usage_costs_nothing_without_tokens() {
+        let price = ModelPrice {
+            input: 2.0,
+            output: 8.0,
+            cached_input: None,
+        };
+        assert_eq!(Usage::default().cost(&price), None);
+        let usage = Usage {
+            completion_tokens: Some(500_000),
+            ..Usage::default()
+        };
+        assert_eq!(usage.cost(&price), Some(4.0));
+    }
+
+    #[test]
+    fn recomputes_the_costs_of_priced_models() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO requests (id, provider_id, request, request_time, response_time, model, prompt_tokens, completion_tokens, cost) VALUES
+                (1, 'a', '{}', '2024-01-01 00:00:00', '2024-01-01 00:00:01', 'gpt', 1000000, 1000000, NULL),
+                (2, 'a', '{}', '2024-01-01 00:00:00', '2024-01-01 00:00:01', 'gpt', 1000000, 0, 1.0),
+                (3, 'a', '{}', '2024-01-01 00:00:00', '2024-01-01 00:00:01', 'llama', 1000000, 0, NULL),
+                (4, 'b', '{}', '2024-01-01 00:00:00', '2024-01-01 00:00:01', 'gpt', 1000000, 0, NULL),
+                (5, 'a', '{}', '2024-01-01 00:00:00', NULL, 'gpt', 1000000, 0, NULL)",
+        )
+        .unwrap();
+        let config: AppConfig = serde_json::from_value(serde_json::json!({
+            "providers": [],
+            "provider": null,
+            "db_path": "",
+            "pricing": { "a": { "gpt": { "input": 1.0, "output": 2.0 } } },
+        }))
+        .unwrap();
+
+        // Row 2 already has the right cost, rows 3 and 4 have no price and 5 isn't finished
+        assert_eq!(recompute_costs_in(&mut conn, &config).unwrap(), 1);
+        let costs: Vec<Option<f64>> = conn
+            .prepare("SELECT cost FROM requests ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(costs, [Some(3.0), Some(1.0), None, None, None]);
+    }
+}
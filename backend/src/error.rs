@@ -0,0 +1,194 @@
+use rocket::http::{ContentType, Status};
+use rocket::response::{self, Responder, Response};
+use rocket::Request;
+use serde_json::json;
+
+use crate::{anthropic, ollama};
+
+/// An error returned by the proxy routes, rendered in the OpenAI error format
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: Status,
+    pub message: String,
+    pub kind: &'static str,
+    pub code: Option<&'static str>,
+}
+
+impl ApiError {
+    pub fn new(status: Status, kind: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            message: message.into(),
+            kind,
+            code: None,
+        }
+    }
+
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn invalid_request(message: impl Into<String>) -> Self {
+        Self::new(Status::BadRequest, "invalid_request_error", message)
+    }
+
+    pub fn unavailable(message: impl Into<String>) -> Self {
+        Self::new(Status::ServiceUnavailable, "service_unavailable", message)
+    }
+
+    /// The upstream couldn't be reached or its response couldn't be read
+    pub fn bad_gateway(message: impl Into<String>) -> Self {
+        Self::new(Status::BadGateway, "upstream_error", message)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "error": {
+                "message": self.message,
+                "type": self.kind,
+                "code": self.code,
+            }
+        })
+    }
+}
+
+fn json_response(status: Status, body: serde_json::Value) -> response::Result<'static> {
+    let body = body.to_string();
+    Response::build()
+        .status(status)
+        .header(ContentType::JSON)
+        .sized_body(body.len(), std::io::Cursor::new(body))
+        .ok()
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        json_response(self.status, self.to_json())
+    }
+}
+
+/// An `ApiError` rendered in the Anthropic error format, for the Messages API route
+#[derive(Debug)]
+pub struct MessagesError(pub ApiError);
+
+impl From<ApiError> for MessagesError {
+    fn from(error: ApiError) -> Self {
+        MessagesError(error)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for MessagesError {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        json_response(self.0.status, anthropic::messages_error(&self.0.to_json()))
+    }
+}
+
+/// An `ApiError` rendered in the Ollama error format, for the Ollama API routes
+#[derive(Debug)]
+pub struct OllamaError(pub ApiError);
+
+impl From<ApiError> for OllamaError {
+    fn from(error: ApiError) -> Self {
+        OllamaError(error)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for OllamaError {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        json_response(self.0.status, ollama::native_error(&self.0.to_json()))
+    }
+}
+
+/// The OpenAI error type matching an HTTP status
+fn error_type(status: u16) -> &'static str {
+    match status {
+        400 | 422 => "invalid_request_error",
+        401 => "authentication_error",
+        403 => "permission_error",
+        404 => "not_found_error",
+        429 => "rate_limit_error",
+        _ => "api_error",
+    }
+}
+
+/// Brings the body of a failed upstream response into the OpenAI error format. Bodies
+/// that already are OpenAI errors are kept as they are.
+pub fn upstream_error(status: u16, body: &str) -> serde_json::Value {
+    let parsed = serde_json::from_str::<serde_json::Value>(body).ok();
+    if let Some(parsed) = &parsed {
+        if parsed["error"]["message"].is_string() {
+            return parsed.clone();
+        }
+    }
+    let message = parsed
+        .as_ref()
+        .and_then(|p| {
+            // Shapes used by common OpenAI-compatible servers
+            [&p["error"], &p["detail"], &p["message"]]
+                .into_iter()
+                .find_map(|m| m.as_str().map(|m| m.to_owned()))
+        })
+        .unwrap_or_else(|| body.trim().to_owned());
+    json!({
+        "error": {
+            "message": message,
+            "type": error_type(status),
+            "code": status,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upstream_error_keeps_openai_errors() {
+        let body = r#"{"error":{"message":"Bad model","type":"invalid_request_error","code":"model_not_found"}}"#;
+        assert_eq!(
+            upstream_error(404, body),
+            serde_json::from_str::<serde_json::Value>(body).unwrap()
+        );
+        // Type and code are optional in the OpenAI format, they aren't filled in
+        let body = r#"{"error":{"message":"Overloaded"}}"#;
+        assert_eq!(
+            upstream_error(503, body),
+            json!({ "error": { "message": "Overloaded" } })
+        );
+    }
+
+    #[test]
+    fn upstream_error_reads_messages_of_other_shapes() {
+        let expected = |message: &str, kind: &str, code: u16| json!({ "error": { "message": message, "type": kind, "code": code } });
+        assert_eq!(
+            upstream_error(404, r#"{"error":"model not found"}"#),
+            expected("model not found", "not_found_error", 404)
+        );
+        assert_eq!(
+            upstream_error(401, r#"{"detail":"Unauthorized"}"#),
+            expected("Unauthorized", "authentication_error", 401)
+        );
+        assert_eq!(
+            upstream_error(429, r#"{"message":"Slow down"}"#),
+            expected("Slow down", "rate_limit_error", 429)
+        );
+        // An error object without a message is passed on as the message
+        assert_eq!(
+            upstream_error(422, r#"{"error":{"code":7}}"#),
+            expected(r#"{"error":{"code":7}}"#, "invalid_request_error", 422)
+        );
+    }
+
+    #[test]
+    fn upstream_error_passes_on_bodies_that_arent_json() {
+        assert_eq!(
+            upstream_error(502, "  <html>Bad Gateway</html>\n"),
+            json!({ "error": { "message": "<html>Bad Gateway</html>", "type": "api_error", "code": 502 } })
+        );
+        assert_eq!(
+            upstream_error(500, ""),
+            json!({ "error": { "message": "", "type": "api_error", "code": 500 } })
+        );
+    }
+}
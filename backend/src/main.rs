@@ -1,28 +1,91 @@
 #[macro_use]
 extern crate rocket;
 
-use config::{AppConfig, Preset, ProviderConfig};
-use log::warn;
+use config::{
+    AppConfig, Budget, LocalTokenizer, ModelAlias, ModelPrice, Preset, ProviderConfig,
+    ProviderKind, SharedConfig,
+};
+use error::{ApiError, MessagesError, OllamaError};
+use indexmap::IndexMap;
+use log::{error, warn};
+use proxy::{
+    apply_preset, authorize, select_provider, send_upstream, upstream_chain, Forwarded,
+    ProxyOverrides, RequestKind, Upstream, UpstreamResponse,
+};
 use reqwest::Client;
 use rocket::fs::NamedFile;
+use rocket::http::{ContentType, Status, StatusClass};
+use rocket::response::status::Custom;
 use rocket::response::stream::TextStream;
 use rocket::serde::Deserialize;
-use rocket::tokio::sync::{mpsc, Mutex};
-use rocket::{get, post, put, routes, serde::json::Json, State};
+use rocket::tokio::sync::mpsc;
+use rocket::{get, post, put, routes, serde::json::Json, FromForm, State};
 use rocket_cors::AllowedOrigins;
-use rusqlite::{params, Connection};
 use serde_json::json;
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, LazyLock};
 use std::time::Instant;
+use tokenizer::TokenMethod;
 
+mod anthropic;
+mod budget;
+mod chat;
 mod config;
+mod db;
+mod error;
+mod models;
+mod ollama;
+mod pool;
+mod proxy;
+mod sse;
+mod stats;
+#[cfg(test)]
+mod test_util;
+mod tokenizer;
+
+/// The tokenizer endpoints of OpenAI-compatible servers such as vLLM
+#[derive(Clone, Copy)]
+enum TokenizerEndpoint {
+    Tokenize,
+    Detokenize,
+}
 
-static DB_CONNECTION: LazyLock<Arc<Mutex<Option<Connection>>>> =
-    LazyLock::new(|| Arc::new(Mutex::new(None)));
+impl TokenizerEndpoint {
+    fn path(self) -> &'static str {
+        match self {
+            TokenizerEndpoint::Tokenize => "tokenize",
+            TokenizerEndpoint::Detokenize => "detokenize",
+        }
+    }
+}
+
+/// Sends `body` to a tokenizer endpoint of `provider`. Fails with the status the upstream
+/// answered with and its body, or `ServiceUnavailable` if it couldn't be reached or didn't
+/// answer with JSON.
+async fn upstream_tokenizer(
+    provider: &ProviderConfig,
+    endpoint: TokenizerEndpoint,
+    body: &HashMap<String, serde_json::Value>,
+) -> Result<serde_json::Value, (Status, String)> {
+    // Only OpenAI-compatible servers offer these endpoints
+    if provider.kind != ProviderKind::OpenAi {
+        return Err((Status::NotImplemented, String::new()));
+    }
 
-type SharedConfig = Arc<Mutex<AppConfig>>;
+    let api_url = format!("{}/{}", provider.api_url, endpoint.path());
+    let res = authorize(provider, Client::new().post(&api_url))
+        .json(body)
+        .send()
+        .await;
+    let unavailable = || (Status::ServiceUnavailable, String::new());
+    let response = res.map_err(|_| unavailable())?;
+    let status = Status::new(response.status().as_u16());
+    let text = response.text().await.map_err(|_| unavailable())?;
+    if status.class() != StatusClass::Success {
+        return Err((status, text));
+    }
+    serde_json::from_str(&text).map_err(|_| unavailable())
+}
 
 /// Tokenizes a prompt using the selected provider, if it supports tokenization
 async fn tokenize_external(
@@ -36,47 +99,168 @@ async fn tokenize_external(
         tokens: Vec<u64>,
     }
 
-    let api_url = format!("{}/tokenize", provider.api_url);
-    let client = Client::new();
-    let mut body = serde_json::json!({
-        "model": model,
-        "prompt": prompt
-    });
-    if let Some(preset) = &provider.preset {
-        if let Some(preset) = provider.presets.iter().find(|p| &p.id == preset) {
-            for prop in preset.overrides.iter() {
-                body[prop.0] = prop.1.clone();
+    let mut body = HashMap::from([
+        ("model".to_string(), json!(model)),
+        ("prompt".to_string(), json!(prompt)),
+    ]);
+    apply_preset(provider, &mut body);
+    let response = upstream_tokenizer(provider, TokenizerEndpoint::Tokenize, &body)
+        .await
+        .map_err(|(status, _)| status)?;
+    serde_json::from_value::<TokenizeResponse>(response)
+        .map(|r| r.tokens)
+        .map_err(|_| Status::ServiceUnavailable)
+}
+
+/// Tokenizes text for the token counts of a request, with the upstream if it offers
+/// tokenization, otherwise with the model's local tokenizer
+async fn tokenize(
+    provider: &ProviderConfig,
+    model: &str,
+    local: Option<&LocalTokenizer>,
+    text: &str,
+) -> Option<(Vec<u64>, TokenMethod)> {
+    if let Ok(tokenized) = tokenize_external(provider, model, text).await {
+        return Some((tokenized, TokenMethod::Upstream));
+    }
+    let tokenized = tokenizer::encode(local?, text).await?;
+    Some((tokenized, TokenMethod::Local))
+}
+
+/// Token counts the upstream reported for a response
+#[derive(Default)]
+struct ReportedUsage {
+    prompt_tokens: Option<u64>,
+    completion_tokens: Option<u64>,
+    cached_tokens: Option<u64>,
+    /// Generated text, tokenized when the upstream doesn't report usage
+    text: String,
+}
+
+impl ReportedUsage {
+    /// Takes the counts of an OpenAI `usage` object, keeping those it leaves out
+    fn read(&mut self, usage: &serde_json::Map<String, serde_json::Value>) {
+        let count = |key: &str| usage.get(key).and_then(|t| t.as_u64());
+        self.prompt_tokens = count("prompt_tokens").or(self.prompt_tokens);
+        self.completion_tokens = count("completion_tokens").or(self.completion_tokens);
+        self.cached_tokens = cached_prompt_tokens(usage).or(self.cached_tokens);
+    }
+
+    /// The usage and generated text of a whole OpenAI response
+    fn of_response(kind: RequestKind, body: &str) -> Self {
+        let mut reported = ReportedUsage::default();
+        let Ok(json) = serde_json::from_str::<serde_json::Value>(body) else {
+            return reported;
+        };
+        if let Some(usage) = json["usage"].as_object() {
+            reported.read(usage);
+        }
+        for choice in json["choices"].as_array().into_iter().flatten() {
+            let text = match kind {
+                RequestKind::Chat => choice["message"]["content"].as_str(),
+                _ => choice["text"].as_str(),
+            };
+            if let Some(text) = text {
+                reported.text.push_str(text);
             }
         }
+        reported
     }
+}
 
-    let res = client
-        .post(&api_url)
-        .header("Authorization", format!("Bearer {}", provider.api_key))
-        .json(&body)
-        .send()
-        .await;
+/// Completes the usage the upstream reported by tokenizing the prompt and generated text
+/// where it left the counts out. Returns the usage and the generation speed in tokens per
+/// second since `started`.
+async fn count_tokens(
+    provider: &ProviderConfig,
+    model: &str,
+    tokenizer: Option<&LocalTokenizer>,
+    prompt: CompletionPrompt,
+    reported: ReportedUsage,
+    started: Instant,
+) -> (db::Usage, Option<i64>) {
+    let mut usage = db::Usage {
+        prompt_tokens: reported.prompt_tokens,
+        completion_tokens: reported.completion_tokens,
+        cached_tokens: reported.cached_tokens,
+        prompt_method: reported.prompt_tokens.map(|_| TokenMethod::Usage),
+        completion_method: reported.completion_tokens.map(|_| TokenMethod::Usage),
+        media: None,
+    };
 
-    match res {
-        Ok(response) => match response.text().await {
-            Ok(text) => {
-                let response: Result<TokenizeResponse, _> = serde_json::from_str(&text);
-                match response {
-                    Ok(response) => Ok(response.tokens),
-                    Err(_) => Err(rocket::http::Status::ServiceUnavailable),
-                }
+    if usage.prompt_tokens.is_none() {
+        let prompt = match prompt {
+            CompletionPrompt::String(s) => Some(s),
+            CompletionPrompt::Array(a) => Some(a.join("\n")),
+            CompletionPrompt::Tokens(tokens) => {
+                usage.prompt_tokens = Some(tokens.len() as u64);
+                usage.prompt_method = Some(TokenMethod::Request);
+                None
             }
-            Err(_) => Err(rocket::http::Status::ServiceUnavailable),
-        },
-        Err(_) => Err(rocket::http::Status::ServiceUnavailable),
+        };
+        if let Some(prompt) = prompt {
+            if let Some((tokens, method)) = tokenize(provider, model, tokenizer, &prompt).await {
+                usage.prompt_tokens = Some(tokens.len() as u64);
+                usage.prompt_method = Some(method);
+            }
+        }
+    }
+
+    if usage.completion_tokens.is_none() {
+        if let Some((tokens, method)) = tokenize(provider, model, tokenizer, &reported.text).await {
+            usage.completion_tokens = Some(tokens.len() as u64);
+            usage.completion_method = Some(method);
+        }
     }
+
+    let speed = usage
+        .completion_tokens
+        .map(|tokens| (tokens as f64 / started.elapsed().as_secs_f64()) as i64);
+    (usage, speed)
+}
+
+/// What is kept of a streamed response for the request log
+#[derive(Default)]
+struct StreamLog {
+    /// Every JSON event of the stream
+    chunks: Vec<serde_json::Map<String, serde_json::Value>>,
+    usage: ReportedUsage,
 }
 
-async fn tokenize(provider: &ProviderConfig, model: &str, prompt: &str) -> Option<Vec<u64>> {
-    if let Ok(tokenized) = tokenize_external(provider, model, prompt).await {
-        return Some(tokenized);
+impl StreamLog {
+    fn record(&mut self, kind: RequestKind, event: &sse::Event) {
+        if event.data == "[DONE]" {
+            return;
+        }
+        let Ok(chunk) = serde_json::from_str::<serde_json::Map<_, _>>(&event.data) else {
+            warn!("Ignoring streamed event that isn't JSON: {}", event.data);
+            return;
+        };
+        if let Some(usage) = chunk.get("usage").and_then(|u| u.as_object()) {
+            self.usage.read(usage);
+        }
+        // Some servers send usage with every chunk, so content is read regardless
+        for choice in chunk
+            .get("choices")
+            .and_then(|c| c.as_array())
+            .into_iter()
+            .flatten()
+        {
+            let text = match kind {
+                RequestKind::Chat => choice["delta"]["content"].as_str(),
+                _ => choice["text"].as_str(),
+            };
+            if let Some(text) = text {
+                self.usage.text.push_str(text);
+            }
+        }
+        self.chunks.push(chunk);
     }
-    None
+}
+
+/// Prompt tokens an OpenAI `usage` object reports as read from the cache
+fn cached_prompt_tokens(usage: &serde_json::Map<String, serde_json::Value>) -> Option<u64> {
+    usage.get("prompt_tokens_details")?["cached_tokens"].as_u64()
 }
 
 enum CompletionPrompt {
@@ -85,41 +269,178 @@ enum CompletionPrompt {
     Tokens(Vec<u64>),
 }
 
-#[post("/api/v1/completions", data = "<body>")]
-async fn proxy_completions(
-    body: Json<HashMap<String, serde_json::Value>>,
-    config: &State<SharedConfig>,
-) -> Result<Result<String, TextStream![String]>, rocket::http::Status> {
-    let config = config.lock().await;
-    let provider_id = match &config.provider {
-        Some(provider_id) => provider_id.clone(),
-        None => return Err(rocket::http::Status::ServiceUnavailable),
-    };
+/// The output of a proxy pipeline
+struct ProxyOutput {
+    status: Status,
+    /// Headers of the upstream response to pass on
+    headers: Vec<(String, String)>,
+    /// Either the whole response body or a channel that yields the streamed response as
+    /// OpenAI SSE text
+    body: Result<String, mpsc::Receiver<String>>,
+    /// Local tokenizer of the model the request was sent with, for estimates ahead of the
+    /// upstream's usage
+    tokenizer: Option<LocalTokenizer>,
+}
 
-    let selected_provider = match config.providers.iter().find(|p| p.id == provider_id) {
-        Some(provider) => provider.clone(),
-        None => return Err(rocket::http::Status::ServiceUnavailable),
-    };
+/// Logs a response whose body couldn't be read and turns it into the error for the client
+async fn read_failure(id: i64, status: Status, e: reqwest::Error) -> ApiError {
+    let error = ApiError::bad_gateway(format!("Upstream request failed: {}", e));
+    db::finish_request(
+        id,
+        Some(status.code),
+        Some((db::Outcome::NetworkError, &error.message)),
+        &error.to_json().to_string(),
+        db::Usage::default(),
+        None,
+        None,
+    )
+    .await;
+    error
+}
 
-    let api_url = format!("{}/completions", selected_provider.api_url);
-    let client = Client::new();
+/// Logs a response the upstream answered with an error status and passes it on, with its
+/// body in the OpenAI error format
+async fn upstream_failure(id: i64, response: UpstreamResponse) -> Result<ProxyOutput, ApiError> {
+    let status = response.status();
+    let headers = response.forwarded_headers();
+    let text = match response.text().await {
+        Ok(text) => text,
+        Err(e) => return Err(read_failure(id, status, e).await),
+    };
+    let body = error::upstream_error(status.code, &text).to_string();
+    db::finish_request(
+        id,
+        Some(status.code),
+        None,
+        &body,
+        db::Usage::default(),
+        None,
+        None,
+    )
+    .await;
+    Ok(ProxyOutput {
+        status,
+        headers,
+        body: Ok(body),
+        tokenizer: None,
+    })
+}
 
-    let mut modified_body = body.into_inner();
-    if let Some(preset) = &selected_provider.preset {
-        if let Some(preset) = selected_provider.presets.iter().find(|p| &p.id == preset) {
-            for prop in preset.overrides.iter() {
-                modified_body.insert(prop.0.to_owned(), prop.1.to_owned());
+/// Passes a streamed upstream response on through the returned channel while recording
+/// it, and logs the request once the stream ends, the upstream breaks off or the client
+/// disconnects
+fn stream_to_log(
+    kind: RequestKind,
+    upstream: Upstream,
+    model: String,
+    prompt: CompletionPrompt,
+    media: Option<chat::MediaParts>,
+    tokenizer: Option<LocalTokenizer>,
+    price: Option<ModelPrice>,
+) -> mpsc::Receiver<String> {
+    let Upstream {
+        provider,
+        response,
+        log_id: id,
+        started,
+        in_flight,
+        ..
+    } = upstream;
+    let status = response.status();
+    let (tx, rx) = mpsc::channel(32);
+    rocket::tokio::spawn(async move {
+        let _in_flight = in_flight;
+        let mut response = response;
+        let mut log = StreamLog::default();
+        let mut decoder = sse::Decoder::default();
+        let mut failure = None;
+        loop {
+            let chunk = rocket::tokio::select! {
+                chunk = response.chunk() => chunk,
+                _ = tx.closed() => {
+                    failure = Some((db::Outcome::Cancelled, "Client disconnected".to_string()));
+                    break;
+                }
+            };
+            let chunk = match chunk {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Upstream stream of request {} broke off: {}", id, e);
+                    failure = Some((
+                        db::Outcome::NetworkError,
+                        format!("Upstream request failed: {}", e),
+                    ));
+                    break;
+                }
+            };
+            for event in decoder.feed(chunk.as_bytes()) {
+                log.record(kind, &event);
+            }
+            if tx.send(chunk).await.is_err() {
+                failure = Some((db::Outcome::Cancelled, "Client disconnected".to_string()));
+                break;
             }
         }
+        // Closes the upstream connection, so an abandoned generation stops there too
+        drop(response);
+        if let Some(event) = decoder.finish() {
+            log.record(kind, &event);
+        }
+        let (usage, speed) = count_tokens(
+            &provider,
+            &model,
+            tokenizer.as_ref(),
+            prompt,
+            log.usage,
+            started,
+        )
+        .await;
+        db::finish_request(
+            id,
+            Some(status.code),
+            failure
+                .as_ref()
+                .map(|(outcome, error)| (*outcome, error.as_str())),
+            &serde_json::to_string(&log.chunks).unwrap(),
+            db::Usage { media, ..usage },
+            speed,
+            price.as_ref(),
+        )
+        .await;
+    });
+    rx
+}
+
+/// Runs an OpenAI text completion request through provider selection, the upstream and
+/// the request log
+async fn completions(
+    mut body: HashMap<String, serde_json::Value>,
+    overrides: &ProxyOverrides,
+    config: &SharedConfig,
+) -> Result<ProxyOutput, ApiError> {
+    let config = config.snapshot().await;
+    let selected_provider = select_provider(&config, overrides, &mut body)?;
+    let chain = budget::enforce(&config, upstream_chain(&config, selected_provider)).await?;
+
+    let client = Client::new();
+    let upstream = send_upstream(&client, &chain, RequestKind::Completion, &body).await?;
+
+    let status = upstream.response.status();
+    if status.class() != StatusClass::Success {
+        let _in_flight = upstream.in_flight;
+        return upstream_failure(upstream.log_id, upstream.response).await;
     }
+    let headers = upstream.response.forwarded_headers();
 
-    let prompt = modified_body
+    let prompt = upstream
+        .body
         .get("prompt")
         .and_then(|v| {
             if let Some(s) = v.as_str() {
                 Some(CompletionPrompt::String(s.to_owned()))
             } else if let Some(a) = v.as_array() {
-                let tokens = a.get(0).is_some_and(|v| v.is_u64());
+                let tokens = a.first().is_some_and(|v| v.is_u64());
                 if tokens {
                     let tokens = a.iter().filter_map(|v| v.as_u64()).collect::<Vec<u64>>();
                     Some(CompletionPrompt::Tokens(tokens))
@@ -137,550 +458,644 @@ async fn proxy_completions(
         })
         .unwrap_or(CompletionPrompt::String("".to_owned()));
 
-    let model = modified_body
+    let model = upstream
+        .body
         .get("model")
         .and_then(|v| v.as_str())
-        .unwrap_or("gpt-3.5-turbo-instruct")
+        .unwrap_or(RequestKind::Completion.default_model())
         .to_owned();
 
-    let stream = modified_body
+    let price = config.price(&upstream.provider.id, &model).cloned();
+    let tokenizer = config.tokenizer(&model).cloned();
+
+    let stream = upstream
+        .body
         .get("stream")
         .and_then(|v| v.as_bool())
         .unwrap_or(false);
 
     if stream {
-        // Force include_usage
-        modified_body
-            .entry("stream_options".to_string())
-            .or_insert(serde_json::json!({}))
-            .as_object_mut()
-            .map(|o| {
-                o.insert("include_usage".to_string(), serde_json::json!(true));
-            });
-    }
-
-    let id = {
-        let db_lock = DB_CONNECTION.lock().await;
-        let db = db_lock.as_ref().unwrap();
-        db
-        .execute(
-            "INSERT INTO requests (provider_id, chat, request, request_time, model) VALUES (?1, ?2, ?3, CURRENT_TIMESTAMP, ?4)",
-            params![
-                provider_id,
-                false,
-                serde_json::to_string(&modified_body).unwrap(),
-                model.clone()
-            ],
-        )
-        .unwrap();
-        // As we have the connection locked, it is guranteed that this is the id of the request we just inserted
-        db.last_insert_rowid()
-    };
-
-    let time = Instant::now();
-
-    if stream {
-        let (tx, mut rx) = mpsc::channel(32);
-        rocket::tokio::spawn(async move {
-            let res = client
-                .post(&api_url)
-                .header(
-                    "Authorization",
-                    format!("Bearer {}", selected_provider.api_key),
+        let body = stream_to_log(
+            RequestKind::Completion,
+            upstream,
+            model,
+            prompt,
+            None,
+            tokenizer.clone(),
+            price,
+        );
+        Ok(ProxyOutput {
+            status,
+            headers,
+            body: Err(body),
+            tokenizer,
+        })
+    } else {
+        let Upstream {
+            provider: selected_provider,
+            response,
+            log_id: id,
+            started: time,
+            in_flight,
+            ..
+        } = upstream;
+        let _in_flight = in_flight;
+        match response.text().await {
+            Ok(text) => {
+                let reported = ReportedUsage::of_response(RequestKind::Completion, &text);
+                let (usage, speed) = count_tokens(
+                    &selected_provider,
+                    &model,
+                    tokenizer.as_ref(),
+                    prompt,
+                    reported,
+                    time,
+                )
+                .await;
+                db::finish_request(
+                    id,
+                    Some(status.code),
+                    None,
+                    &text,
+                    usage,
+                    speed,
+                    price.as_ref(),
                 )
-                .json(&modified_body)
-                .send()
                 .await;
+                Ok(ProxyOutput {
+                    status,
+                    headers,
+                    body: Ok(text),
+                    tokenizer,
+                })
+            }
+            Err(e) => Err(read_failure(id, status, e).await),
+        }
+    }
+}
 
-            let mut log = Vec::new();
-            let mut prompt_tokens = match prompt {
-                CompletionPrompt::Tokens(ref tokens) => Some(tokens.len() as u64),
-                _ => None,
-            };
-            let mut completion_tokens = None;
-            let mut speed = None;
-            let mut text_to_count = String::new();
-
-            match res {
-                Ok(mut response) => {
-                    while let Some(chunk) = response.chunk().await.unwrap() {
-                        let chunk = String::from_utf8_lossy(&chunk).to_string();
-                        if let Some((_, body)) = chunk.split_once(':') {
-                            let chunk: Result<serde_json::Map<String, serde_json::Value>, _> =
-                                serde_json::from_str(body);
-                            if let Ok(chunk) = chunk {
-                                if let Some(usage) = chunk.get("usage").and_then(|u| u.as_object())
-                                {
-                                    prompt_tokens = usage
-                                        .get("prompt_tokens")
-                                        .and_then(|t| t.as_u64())
-                                        .or(prompt_tokens);
-                                    completion_tokens = usage
-                                        .get("completion_tokens")
-                                        .and_then(|t| t.as_u64())
-                                        .or(completion_tokens);
-                                } else if let Some(choices) =
-                                    chunk.get("choices").and_then(|c| c.as_array())
-                                {
-                                    for choice in choices {
-                                        if let Some(text) =
-                                            choice.get("text").and_then(|c| c.as_str())
-                                        {
-                                            text_to_count.push_str(text);
-                                        }
-                                    }
-                                }
-                                log.push(chunk);
-                            }
-                        }
-                        let _ = tx.send(chunk).await;
-                    }
+/// Runs an OpenAI chat completion request through provider selection, the upstream and
+/// the request log
+async fn chat_completions(
+    mut body: HashMap<String, serde_json::Value>,
+    overrides: &ProxyOverrides,
+    config: &SharedConfig,
+) -> Result<ProxyOutput, ApiError> {
+    let config = config.snapshot().await;
+    let selected_provider = select_provider(&config, overrides, &mut body)?;
+    let chain = budget::enforce(&config, upstream_chain(&config, selected_provider)).await?;
 
-                    if prompt_tokens.is_none() {
-                        let prompt = match prompt {
-                            CompletionPrompt::String(s) => s,
-                            CompletionPrompt::Array(a) => a.join("\n"),
-                            CompletionPrompt::Tokens(_) => unreachable!(),
-                        };
-                        if let Some(tokens) = tokenize(&selected_provider, &model, &prompt).await {
-                            prompt_tokens = Some(tokens.len() as u64);
-                        }
-                    }
+    let client = Client::new();
+    let upstream = send_upstream(&client, &chain, RequestKind::Chat, &body).await?;
 
-                    if completion_tokens.is_none() {
-                        if let Some(tokens) =
-                            tokenize(&selected_provider, &model, &text_to_count).await
-                        {
-                            completion_tokens = Some(tokens.len() as u64);
-                        }
-                    }
+    let status = upstream.response.status();
+    if status.class() != StatusClass::Success {
+        let _in_flight = upstream.in_flight;
+        return upstream_failure(upstream.log_id, upstream.response).await;
+    }
+    let headers = upstream.response.forwarded_headers();
 
-                    if let Some(completion_tokens) = completion_tokens {
-                        let elapsed = time.elapsed();
-                        let elapsed = elapsed.as_secs_f64();
-                        speed = Some((completion_tokens as f64 / elapsed) as i64);
-                    }
+    let model = upstream
+        .body
+        .get("model")
+        .and_then(|v| v.as_str())
+        .unwrap_or(RequestKind::Chat.default_model())
+        .to_owned();
 
-                    DB_CONNECTION.lock().await.as_ref().unwrap()
-                        .execute(
-                            "UPDATE requests SET response = ?2, response_time = CURRENT_TIMESTAMP, prompt_tokens = ?3, completion_tokens = ?4, speed = ?5 WHERE id = ?1",
-                            params![id.to_string(), serde_json::to_string(&log).unwrap(), prompt_tokens, completion_tokens, speed],
-                        )
-                        .unwrap();
-                }
-                Err(_) => {
-                    let _ = tx.send("Error streaming response".to_string()).await;
-                }
-            }
-        });
+    let prompt = chat::prompt(upstream.body.get("messages"));
 
-        Ok(Err(rocket::response::stream::TextStream! {
-            while let Some(chunk) = rx.recv().await {
-                yield chunk;
-            }
-        }))
-    } else {
-        let res = client
-            .post(&api_url)
-            .header(
-                "Authorization",
-                format!("Bearer {}", selected_provider.api_key),
-            )
-            .json(&modified_body)
-            .send()
-            .await;
-
-        match res {
-            Ok(response) => match response.text().await {
-                Ok(text) => {
-                    let mut prompt_tokens = match prompt {
-                        CompletionPrompt::Tokens(ref tokens) => Some(tokens.len() as u64),
-                        _ => None,
-                    };
-                    let mut completion_tokens = None;
-                    let mut speed = None;
-                    let mut text_to_count = String::new();
-                    let json: Result<serde_json::Map<String, serde_json::Value>, _> =
-                        serde_json::from_str(&text);
-                    if let Ok(json) = json {
-                        if let Some(usage) = json.get("usage").and_then(|u| u.as_object()) {
-                            prompt_tokens = usage
-                                .get("prompt_tokens")
-                                .and_then(|t| t.as_u64())
-                                .or(prompt_tokens);
-                            completion_tokens = usage
-                                .get("completion_tokens")
-                                .and_then(|t| t.as_u64())
-                                .or(completion_tokens);
-                        } else if let Some(choices) = json.get("choices").and_then(|c| c.as_array())
-                        {
-                            for choice in choices {
-                                if let Some(text) = choice.get("text").and_then(|c| c.as_str()) {
-                                    text_to_count.push_str(text);
-                                }
-                            }
-                        }
-                    }
+    let price = config.price(&upstream.provider.id, &model).cloned();
+    let tokenizer = config.tokenizer(&model).cloned();
 
-                    if prompt_tokens.is_none() {
-                        let prompt = match prompt {
-                            CompletionPrompt::String(s) => s,
-                            CompletionPrompt::Array(a) => a.join("\n"),
-                            CompletionPrompt::Tokens(_) => unreachable!(),
-                        };
-                        if let Some(tokens) = tokenize(&selected_provider, &model, &prompt).await {
-                            prompt_tokens = Some(tokens.len() as u64);
-                        }
-                    }
-                    if completion_tokens.is_none() {
-                        if let Some(tokens) =
-                            tokenize(&selected_provider, &model, &text_to_count).await
-                        {
-                            completion_tokens = Some(tokens.len() as u64);
-                        }
-                    }
+    let stream = upstream
+        .body
+        .get("stream")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
 
-                    if let Some(completion_tokens) = completion_tokens {
-                        let elapsed = time.elapsed();
-                        let elapsed = elapsed.as_secs_f64();
-                        speed = Some((completion_tokens as f64 / elapsed) as i64);
-                    }
+    if stream {
+        let body = stream_to_log(
+            RequestKind::Chat,
+            upstream,
+            model,
+            CompletionPrompt::String(prompt.text),
+            Some(prompt.media),
+            tokenizer.clone(),
+            price,
+        );
+        Ok(ProxyOutput {
+            status,
+            headers,
+            body: Err(body),
+            tokenizer,
+        })
+    } else {
+        let Upstream {
+            provider: selected_provider,
+            response,
+            log_id: id,
+            started: time,
+            in_flight,
+            ..
+        } = upstream;
+        let _in_flight = in_flight;
+        match response.text().await {
+            Ok(text) => {
+                let reported = ReportedUsage::of_response(RequestKind::Chat, &text);
+                let (usage, speed) = count_tokens(
+                    &selected_provider,
+                    &model,
+                    tokenizer.as_ref(),
+                    CompletionPrompt::String(prompt.text),
+                    reported,
+                    time,
+                )
+                .await;
+                let usage = db::Usage {
+                    media: Some(prompt.media),
+                    ..usage
+                };
+                db::finish_request(
+                    id,
+                    Some(status.code),
+                    None,
+                    &text,
+                    usage,
+                    speed,
+                    price.as_ref(),
+                )
+                .await;
+                Ok(ProxyOutput {
+                    status,
+                    headers,
+                    body: Ok(text),
+                    tokenizer,
+                })
+            }
+            Err(e) => Err(read_failure(id, status, e).await),
+        }
+    }
+}
 
-                    DB_CONNECTION.lock().await.as_ref().unwrap()
-                        .execute(
-                            "UPDATE requests SET response = ?1, response_time = CURRENT_TIMESTAMP, prompt_tokens = ?3, completion_tokens = ?4, speed = ?5 WHERE id = ?2",
-                            params![text.clone(), id.to_string(), prompt_tokens, completion_tokens, speed],
-                        )
-                        .unwrap();
-                    Ok(Ok(text))
+/// Answers an OpenAI API request with the output of its pipeline
+fn openai_reply(output: ProxyOutput) -> Forwarded<Result<String, TextStream![String]>> {
+    let (content_type, body) = match output.body {
+        Ok(text) => (ContentType::JSON, Ok(text)),
+        Err(mut rx) => (
+            ContentType::EventStream,
+            Err(rocket::response::stream::TextStream! {
+                while let Some(chunk) = rx.recv().await {
+                    yield chunk;
                 }
-                Err(_) => Err(rocket::http::Status::ServiceUnavailable),
-            },
-            Err(_) => Err(rocket::http::Status::ServiceUnavailable),
-        }
+            }),
+        ),
+    };
+    Forwarded {
+        status: output.status,
+        content_type,
+        headers: output.headers,
+        body,
     }
 }
 
+#[post("/api/v1/completions", data = "<body>")]
+async fn proxy_completions(
+    body: Json<HashMap<String, serde_json::Value>>,
+    overrides: ProxyOverrides,
+    config: &State<SharedConfig>,
+) -> Result<Forwarded<Result<String, TextStream![String]>>, ApiError> {
+    let output = completions(body.into_inner(), &overrides, config).await?;
+    Ok(openai_reply(output))
+}
+
 #[post("/api/v1/chat/completions", data = "<body>")]
 async fn proxy_chat_completions(
     body: Json<HashMap<String, serde_json::Value>>,
+    overrides: ProxyOverrides,
     config: &State<SharedConfig>,
-) -> Result<Result<String, TextStream![String]>, rocket::http::Status> {
-    let config = config.lock().await;
-    let provider_id = match &config.provider {
-        Some(provider_id) => provider_id.clone(),
-        None => return Err(rocket::http::Status::ServiceUnavailable),
-    };
+) -> Result<Forwarded<Result<String, TextStream![String]>>, ApiError> {
+    let output = chat_completions(body.into_inner(), &overrides, config).await?;
+    Ok(openai_reply(output))
+}
 
-    let selected_provider = match config.providers.iter().find(|p| p.id == provider_id) {
-        Some(provider) => provider.clone(),
-        None => return Err(rocket::http::Status::ServiceUnavailable),
-    };
+/// Embeddings endpoint. Only the token usage is logged, not the vectors.
+#[post("/api/v1/embeddings", data = "<body>")]
+async fn proxy_embeddings(
+    body: Json<HashMap<String, serde_json::Value>>,
+    overrides: ProxyOverrides,
+    config: &State<SharedConfig>,
+) -> Result<Forwarded<String>, ApiError> {
+    let config = config.snapshot().await;
+    let mut body = body.into_inner();
+    let selected_provider = select_provider(&config, &overrides, &mut body)?;
+    let chain = budget::enforce(&config, upstream_chain(&config, selected_provider)).await?;
 
-    let api_url = format!("{}/chat/completions", selected_provider.api_url);
     let client = Client::new();
-
-    let mut modified_body = body.into_inner();
-    if let Some(preset) = &selected_provider.preset {
-        if let Some(preset) = selected_provider.presets.iter().find(|p| &p.id == preset) {
-            for prop in preset.overrides.iter() {
-                modified_body.insert(prop.0.to_owned(), prop.1.to_owned());
-            }
-        }
+    let Upstream {
+        provider: selected_provider,
+        body: modified_body,
+        response,
+        log_id: id,
+        in_flight,
+        ..
+    } = send_upstream(&client, &chain, RequestKind::Embedding, &body).await?;
+    let _in_flight = in_flight;
+
+    let status = response.status();
+    if status.class() != StatusClass::Success {
+        let output = upstream_failure(id, response).await?;
+        return Ok(Forwarded {
+            status: output.status,
+            content_type: ContentType::JSON,
+            headers: output.headers,
+            body: output.body.unwrap_or_default(),
+        });
     }
+    let headers = response.forwarded_headers();
+    let text = match response.text().await {
+        Ok(text) => text,
+        Err(e) => return Err(read_failure(id, status, e).await),
+    };
 
+    let mut logged = match serde_json::from_str::<serde_json::Value>(&text) {
+        Ok(json) => json,
+        Err(_) => json!({ "error": text }),
+    };
+    let mut prompt_tokens = logged["usage"]["prompt_tokens"].as_u64();
     let model = modified_body
         .get("model")
         .and_then(|v| v.as_str())
-        .unwrap_or("gpt-3.5-turbo")
-        .to_owned();
+        .unwrap_or(RequestKind::Embedding.default_model());
+    // Each vector is replaced by its size, they would take most of the database otherwise
+    if let Some(data) = logged["data"].as_array_mut() {
+        for item in data {
+            if let Some(embedding) = item.as_object_mut().and_then(|i| i.remove("embedding")) {
+                if let Some(vector) = embedding.as_array() {
+                    item["dimensions"] = json!(vector.len());
+                }
+            }
+        }
+    }
 
-    let messages = modified_body
-        .get("messages")
-        .and_then(|v| {
-            v.as_array().and_then(|m| {
-                m.into_iter()
-                    .map(|v| {
-                        v.as_object().and_then(|st| {
-                            st.get("content")
-                                .and_then(|c| c.as_str().map(|s| s.to_owned()))
-                        })
-                    })
-                    .collect()
-            })
-        })
-        .unwrap_or(Vec::new());
+    let mut prompt_method = prompt_tokens.map(|_| TokenMethod::Usage);
+    if prompt_tokens.is_none() {
+        let tokenizer = config.tokenizer(model);
+        let input = match modified_body.get("input") {
+            Some(serde_json::Value::String(s)) => Some(s.clone()),
+            Some(serde_json::Value::Array(a)) if a.iter().all(|v| v.is_string()) => Some(
+                a.iter()
+                    .filter_map(|v| v.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+            _ => None,
+        };
+        if let Some(input) = input {
+            if let Some((tokens, method)) =
+                tokenize(&selected_provider, model, tokenizer, &input).await
+            {
+                prompt_tokens = Some(tokens.len() as u64);
+                prompt_method = Some(method);
+            }
+        }
+    }
 
-    let stream = modified_body
-        .get("stream")
-        .and_then(|v| v.as_bool())
-        .unwrap_or(false);
+    db::finish_request(
+        id,
+        Some(status.code),
+        None,
+        &logged.to_string(),
+        db::Usage {
+            prompt_tokens,
+            prompt_method,
+            ..db::Usage::default()
+        },
+        None,
+        config.price(&selected_provider.id, model),
+    )
+    .await;
+    Ok(Forwarded {
+        status,
+        content_type: ContentType::JSON,
+        headers,
+        body: text,
+    })
+}
 
-    if stream {
-        // Force include_usage
-        modified_body
-            .entry("stream_options".to_string())
-            .or_insert(serde_json::json!({}))
-            .as_object_mut()
-            .map(|o| {
-                o.insert("include_usage".to_string(), serde_json::json!(true));
-            });
+/// Serves a request to a tokenizer endpoint with the first provider of the failover chain
+/// that offers it, otherwise with the model's local tokenizer. Nothing is logged, these
+/// requests cost nothing.
+async fn tokenizer_request(
+    endpoint: TokenizerEndpoint,
+    mut body: HashMap<String, serde_json::Value>,
+    overrides: &ProxyOverrides,
+    config: &SharedConfig,
+) -> Result<Custom<Json<serde_json::Value>>, ApiError> {
+    let config = config.snapshot().await;
+    let selected_provider = select_provider(&config, overrides, &mut body)?;
+
+    for provider in upstream_chain(&config, selected_provider) {
+        let mut body = body.clone();
+        apply_preset(&provider, &mut body);
+        match upstream_tokenizer(&provider, endpoint, &body).await {
+            Ok(response) => return Ok(Custom(Status::Ok, Json(response))),
+            // The endpoint is missing or the provider is unavailable, try the next one
+            Err((status, _))
+                if matches!(status.code, 404 | 405 | 429 | 501)
+                    || status.class() == StatusClass::ServerError => {}
+            Err((status, text)) => {
+                return Ok(Custom(
+                    status,
+                    Json(error::upstream_error(status.code, &text)),
+                ))
+            }
+        }
     }
 
-    let id = {
-        let db_lock = DB_CONNECTION.lock().await;
-        let db = db_lock.as_ref().unwrap();
-        db
-        .execute(
-            "INSERT INTO requests (provider_id, chat, request, request_time, model) VALUES (?1, ?2, ?3, CURRENT_TIMESTAMP, ?4)",
-            params![
-                provider_id,
-                true,
-                serde_json::to_string(&modified_body).unwrap(),
-                model.clone()
-            ],
+    let model = body.get("model").and_then(|m| m.as_str()).unwrap_or("");
+    let Some(local) = config.tokenizer(model) else {
+        return Err(ApiError::new(
+            Status::NotImplemented,
+            "invalid_request_error",
+            format!(
+                "No provider offers /{} and there is no local tokenizer for model '{}'",
+                endpoint.path(),
+                model
+            ),
         )
-        .unwrap();
-        // As we have the connection locked, it is guranteed that this is the id of the request we just inserted
-        db.last_insert_rowid()
+        .with_code("tokenizer_unavailable"));
     };
+    let failed = || {
+        ApiError::new(
+            Status::InternalServerError,
+            "server_error",
+            format!("The local tokenizer for model '{}' failed", model),
+        )
+    };
+    let response = match endpoint {
+        TokenizerEndpoint::Tokenize => {
+            let text = if let Some(prompt) = body.get("prompt").and_then(|p| p.as_str()) {
+                prompt.to_owned()
+            } else if let Some(messages) = body.get("messages") {
+                chat::prompt(Some(messages)).text
+            } else {
+                return Err(ApiError::invalid_request(
+                    "Either 'prompt' or 'messages' is required",
+                ));
+            };
+            let tokens = tokenizer::encode(local, &text).await.ok_or_else(failed)?;
+            json!({ "count": tokens.len(), "tokens": tokens })
+        }
+        TokenizerEndpoint::Detokenize => {
+            let tokens: Vec<u64> = body
+                .get("tokens")
+                .and_then(|t| serde_json::from_value(t.clone()).ok())
+                .ok_or_else(|| {
+                    ApiError::invalid_request("'tokens' must be an array of token ids")
+                })?;
+            let prompt = tokenizer::decode(local, &tokens).await.ok_or_else(failed)?;
+            json!({ "prompt": prompt })
+        }
+    };
+    Ok(Custom(Status::Ok, Json(response)))
+}
 
-    let time = Instant::now();
-
-    if stream {
-        let (tx, mut rx) = mpsc::channel(32);
-        rocket::tokio::spawn(async move {
-            let res = client
-                .post(&api_url)
-                .header(
-                    "Authorization",
-                    format!("Bearer {}", selected_provider.api_key),
-                )
-                .json(&modified_body)
-                .send()
-                .await;
-
-            let mut log = Vec::new();
-            let mut prompt_tokens = None;
-            let mut completion_tokens = None;
-            let mut speed = None;
-            let mut text_to_count = String::new();
-
-            match res {
-                Ok(mut response) => {
-                    while let Some(chunk) = response.chunk().await.unwrap() {
-                        let chunk = String::from_utf8_lossy(&chunk).to_string();
-                        if let Some((_, body)) = chunk.split_once(':') {
-                            let chunk: Result<serde_json::Map<String, serde_json::Value>, _> =
-                                serde_json::from_str(body);
-                            if let Ok(chunk) = chunk {
-                                if let Some(usage) = chunk.get("usage").and_then(|u| u.as_object())
-                                {
-                                    prompt_tokens = usage
-                                        .get("prompt_tokens")
-                                        .and_then(|t| t.as_u64())
-                                        .or(prompt_tokens);
-                                    completion_tokens = usage
-                                        .get("completion_tokens")
-                                        .and_then(|t| t.as_u64())
-                                        .or(completion_tokens);
-                                } else if let Some(choices) =
-                                    chunk.get("choices").and_then(|c| c.as_array())
-                                {
-                                    for choice in choices {
-                                        if let Some(text) = choice.get("delta").and_then(|d| {
-                                            d.as_object().and_then(|o| {
-                                                o.get("content").and_then(|t| t.as_str())
-                                            })
-                                        }) {
-                                            text_to_count.push_str(text);
-                                        }
-                                    }
-                                }
-                                log.push(chunk);
-                            }
-                        }
-                        let _ = tx.send(chunk).await;
-                    }
+/// Tokenize endpoint in the format of vLLM, for a `prompt` or chat `messages`
+#[post("/api/v1/tokenize", data = "<body>")]
+async fn proxy_tokenize(
+    body: Json<HashMap<String, serde_json::Value>>,
+    overrides: ProxyOverrides,
+    config: &State<SharedConfig>,
+) -> Result<Custom<Json<serde_json::Value>>, ApiError> {
+    tokenizer_request(
+        TokenizerEndpoint::Tokenize,
+        body.into_inner(),
+        &overrides,
+        config,
+    )
+    .await
+}
 
-                    if prompt_tokens.is_none() {
-                        if let Some(tokens) =
-                            tokenize(&selected_provider, &model, &messages.join("\n")).await
-                        {
-                            prompt_tokens = Some(tokens.len() as u64);
-                        }
-                    }
+/// Detokenize endpoint in the format of vLLM, turns `tokens` back into text
+#[post("/api/v1/detokenize", data = "<body>")]
+async fn proxy_detokenize(
+    body: Json<HashMap<String, serde_json::Value>>,
+    overrides: ProxyOverrides,
+    config: &State<SharedConfig>,
+) -> Result<Custom<Json<serde_json::Value>>, ApiError> {
+    tokenizer_request(
+        TokenizerEndpoint::Detokenize,
+        body.into_inner(),
+        &overrides,
+        config,
+    )
+    .await
+}
 
-                    if completion_tokens.is_none() {
-                        if let Some(tokens) =
-                            tokenize(&selected_provider, &model, &text_to_count).await
-                        {
-                            completion_tokens = Some(tokens.len() as u64);
+/// Anthropic Messages API endpoint. Requests are converted to OpenAI chat completions,
+/// served like `proxy_chat_completions` and the responses converted back.
+#[post("/api/v1/messages", data = "<body>")]
+async fn proxy_messages(
+    body: Json<serde_json::Value>,
+    overrides: ProxyOverrides,
+    config: &State<SharedConfig>,
+) -> Result<Forwarded<Result<String, TextStream![String]>>, MessagesError> {
+    let body = anthropic::chat_request(&body.into_inner());
+    let prompt = chat::prompt(body.get("messages"));
+    let output = chat_completions(body, &overrides, config).await?;
+    // message_start comes before the upstream's usage, estimate the prompt for it with the
+    // tokenizer of the model the request was routed to
+    let mut input_tokens = 0;
+    if let (Err(_), Some(tokenizer)) = (&output.body, &output.tokenizer) {
+        if let Some(tokens) = tokenizer::encode(tokenizer, &prompt.text).await {
+            input_tokens = tokens.len() as u64;
+        }
+    }
+    let (content_type, body) = match output.body {
+        Ok(text) => (
+            ContentType::JSON,
+            Ok(match serde_json::from_str::<serde_json::Value>(&text) {
+                Ok(response) if response.get("error").is_some() => {
+                    anthropic::messages_error(&response).to_string()
+                }
+                Ok(response) => anthropic::messages_response(&response).to_string(),
+                Err(_) => text,
+            }),
+        ),
+        Err(mut rx) => (
+            ContentType::EventStream,
+            Err(rocket::response::stream::TextStream! {
+                let mut decoder = sse::Decoder::default();
+                let mut translator = anthropic::MessagesStreamTranslator::new(input_tokens);
+                while let Some(chunk) = rx.recv().await {
+                    for event in decoder.feed(chunk.as_bytes()) {
+                        for frame in translator.event(&event) {
+                            yield frame;
                         }
                     }
-
-                    if let Some(completion_tokens) = completion_tokens {
-                        let elapsed = time.elapsed();
-                        let elapsed = elapsed.as_secs_f64();
-                        speed = Some((completion_tokens as f64 / elapsed) as i64);
+                }
+                if let Some(event) = decoder.finish() {
+                    for frame in translator.event(&event) {
+                        yield frame;
                     }
-
-                    DB_CONNECTION.lock().await.as_ref().unwrap()
-                        .execute(
-                            "UPDATE requests SET response = ?2, response_time = CURRENT_TIMESTAMP, prompt_tokens = ?3, completion_tokens = ?4, speed = ?5 WHERE id = ?1",
-                            params![id.to_string(), serde_json::to_string(&log).unwrap(), prompt_tokens, completion_tokens, speed],
-                        )
-                        .unwrap();
                 }
-                Err(_) => {
-                    let _ = tx.send("Error streaming response".to_string()).await;
+                for frame in translator.finish() {
+                    yield frame;
                 }
-            }
-        });
+            }),
+        ),
+    };
+    Ok(Forwarded {
+        status: output.status,
+        content_type,
+        headers: output.headers,
+        body,
+    })
+}
 
-        Ok(Err(rocket::response::stream::TextStream! {
-            while let Some(chunk) = rx.recv().await {
-                yield chunk;
-            }
-        }))
+/// Lists the models of the selected provider, or of every provider when aggregation is
+/// enabled in the config or requested with `aggregate`, as an OpenAI model list
+async fn list_models(
+    aggregate: Option<bool>,
+    overrides: &ProxyOverrides,
+    config: &SharedConfig,
+) -> Result<serde_json::Map<String, serde_json::Value>, ApiError> {
+    let config = config.snapshot().await;
+
+    let mut response = if aggregate.unwrap_or(config.models.aggregate) {
+        let models = models::aggregate_models(&config).await;
+        serde_json::Map::from_iter([
+            ("object".to_string(), json!("list")),
+            ("data".to_string(), json!(models)),
+        ])
     } else {
-        let res = client
-            .post(&api_url)
-            .header(
-                "Authorization",
-                format!("Bearer {}", selected_provider.api_key),
-            )
-            .json(&modified_body)
-            .send()
-            .await;
-
-        match res {
-            Ok(response) => match response.text().await {
-                Ok(text) => {
-                    let mut prompt_tokens = None;
-                    let mut completion_tokens = None;
-                    let mut speed = None;
-                    let mut text_to_count = String::new();
-                    let json: Result<serde_json::Map<String, serde_json::Value>, _> =
-                        serde_json::from_str(&text);
-                    if let Ok(json) = json {
-                        if let Some(usage) = json.get("usage").and_then(|u| u.as_object()) {
-                            prompt_tokens = usage
-                                .get("prompt_tokens")
-                                .and_then(|t| t.as_u64())
-                                .or(prompt_tokens);
-                            completion_tokens = usage
-                                .get("completion_tokens")
-                                .and_then(|t| t.as_u64())
-                                .or(completion_tokens);
-                        } else if let Some(choices) = json.get("choices").and_then(|c| c.as_array())
-                        {
-                            for choice in choices {
-                                if let Some(text) = choice.get("message").and_then(|d| {
-                                    d.as_object()
-                                        .and_then(|o| o.get("content").and_then(|t| t.as_str()))
-                                }) {
-                                    text_to_count.push_str(text);
-                                }
-                            }
-                        }
-                    }
-
-                    if prompt_tokens.is_none() {
-                        if let Some(tokens) =
-                            tokenize(&selected_provider, &model, &messages.join("\n")).await
-                        {
-                            prompt_tokens = Some(tokens.len() as u64);
-                        }
-                    }
-                    if completion_tokens.is_none() {
-                        if let Some(tokens) =
-                            tokenize(&selected_provider, &model, &text_to_count).await
-                        {
-                            completion_tokens = Some(tokens.len() as u64);
-                        }
-                    }
-
-                    if let Some(completion_tokens) = completion_tokens {
-                        let elapsed = time.elapsed();
-                        let elapsed = elapsed.as_secs_f64();
-                        speed = Some((completion_tokens as f64 / elapsed) as i64);
-                    }
+        let selected_provider = select_provider(&config, overrides, &mut HashMap::new())?;
+        // A pool lists the models of whichever member it picks
+        let selected_provider = match upstream_chain(&config, selected_provider)
+            .into_iter()
+            .next()
+        {
+            Some(provider) => provider,
+            None => return Err(ApiError::unavailable("No provider available")),
+        };
+        models::fetch_models(&Client::new(), &selected_provider)
+            .await
+            .map_err(|e| ApiError::unavailable(format!("Upstream request failed: {}", e)))?
+    };
 
-                    DB_CONNECTION.lock().await.as_ref().unwrap()
-                        .execute(
-                            "UPDATE requests SET response = ?1, response_time = CURRENT_TIMESTAMP, prompt_tokens = ?3, completion_tokens = ?4, speed = ?5 WHERE id = ?2",
-                            params![text.clone(), id.to_string(), prompt_tokens, completion_tokens, speed],
-                        )
-                        .unwrap();
-                    Ok(Ok(text))
-                }
-                Err(_) => Err(rocket::http::Status::ServiceUnavailable),
-            },
-            Err(_) => Err(rocket::http::Status::ServiceUnavailable),
+    if !config.aliases.is_empty() {
+        let models = response
+            .entry("data")
+            .or_insert_with(|| json!([]))
+            .as_array_mut();
+        if let Some(models) = models {
+            for alias in config.aliases.keys() {
+                models.push(json!({
+                    "id": alias,
+                    "object": "model",
+                    "owned_by": "aiswitch",
+                }));
+            }
         }
     }
+    Ok(response)
 }
 
-#[get("/api/v1/models")]
+#[get("/api/v1/models?<aggregate>")]
 async fn proxy_models(
+    aggregate: Option<bool>,
+    overrides: ProxyOverrides,
     config: &State<SharedConfig>,
-) -> Result<Json<serde_json::Value>, rocket::http::Status> {
-    let config = config.lock().await;
-    let selected_provider_id = match &config.provider {
-        Some(provider_id) => provider_id.clone(),
-        None => return Err(rocket::http::Status::ServiceUnavailable),
-    };
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let models = list_models(aggregate, &overrides, config).await?;
+    Ok(Json(serde_json::Value::Object(models)))
+}
 
-    let selected_provider = match config
-        .providers
-        .iter()
-        .find(|p| p.id == selected_provider_id)
-    {
-        Some(provider) => provider.clone(),
-        None => return Err(rocket::http::Status::ServiceUnavailable),
+/// Answers an Ollama API request of `kind` for `model` with the output of its OpenAI
+/// pipeline, streamed as one JSON object per line
+fn ollama_reply(
+    kind: RequestKind,
+    model: serde_json::Value,
+    output: ProxyOutput,
+) -> Forwarded<Result<String, TextStream![String]>> {
+    let (content_type, body) = match output.body {
+        Ok(text) => (
+            ContentType::JSON,
+            Ok(match serde_json::from_str::<serde_json::Value>(&text) {
+                Ok(response) => ollama::native_response(kind, &response).to_string(),
+                Err(_) => text,
+            }),
+        ),
+        Err(mut rx) => (
+            ContentType::new("application", "x-ndjson"),
+            Err(rocket::response::stream::TextStream! {
+                let mut decoder = sse::Decoder::default();
+                let mut translator = ollama::NativeStreamTranslator::new(kind, model);
+                while let Some(chunk) = rx.recv().await {
+                    for event in decoder.feed(chunk.as_bytes()) {
+                        for line in translator.event(&event) {
+                            yield line;
+                        }
+                    }
+                }
+                if let Some(event) = decoder.finish() {
+                    for line in translator.event(&event) {
+                        yield line;
+                    }
+                }
+                for line in translator.finish() {
+                    yield line;
+                }
+            }),
+        ),
     };
+    Forwarded {
+        status: output.status,
+        content_type,
+        headers: output.headers,
+        body,
+    }
+}
 
-    let api_url = format!("{}/models", selected_provider.api_url);
-    let client = Client::new();
+/// Ollama chat endpoint, served like `proxy_chat_completions`
+#[post("/api/chat", data = "<body>")]
+async fn ollama_chat(
+    body: Json<serde_json::Value>,
+    overrides: ProxyOverrides,
+    config: &State<SharedConfig>,
+) -> Result<Forwarded<Result<String, TextStream![String]>>, OllamaError> {
+    let body = ollama::openai_request(RequestKind::Chat, &body.into_inner());
+    let model = body["model"].clone();
+    let output = chat_completions(body, &overrides, config).await?;
+    Ok(ollama_reply(RequestKind::Chat, model, output))
+}
 
-    let res = client
-        .get(&api_url)
-        .header(
-            "Authorization",
-            format!("Bearer {}", selected_provider.api_key),
-        )
-        .send()
-        .await;
+/// Ollama generate endpoint, served like `proxy_completions`
+#[post("/api/generate", data = "<body>")]
+async fn ollama_generate(
+    body: Json<serde_json::Value>,
+    overrides: ProxyOverrides,
+    config: &State<SharedConfig>,
+) -> Result<Forwarded<Result<String, TextStream![String]>>, OllamaError> {
+    let body = ollama::openai_request(RequestKind::Completion, &body.into_inner());
+    let model = body["model"].clone();
+    let output = completions(body, &overrides, config).await?;
+    Ok(ollama_reply(RequestKind::Completion, model, output))
+}
 
-    match res {
-        Ok(response) => match response.text().await {
-            Ok(text) => {
-                let mut response: serde_json::Map<_, _> =
-                    serde_json::from_str(&text).unwrap_or_default();
-                if let Some(models) = response.get_mut("data").and_then(|m| m.as_array_mut()) {
-                    if let Some(override_model) = selected_provider.preset.as_ref().and_then(|p| {
-                        selected_provider
-                            .presets
-                            .iter()
-                            .find(|preset| &preset.id == p)
-                            .and_then(|preset| {
-                                preset.overrides.get("model").and_then(|m| m.as_str())
-                            })
-                    }) {
-                        models.retain(|m| {
-                            m.as_object()
-                                .and_then(|m| m.get("id").and_then(|id| id.as_str()))
-                                .map_or(true, |id| id == override_model)
-                        });
-                    }
-                }
-                Ok(Json(serde_json::Value::Object(response)))
-            }
-            Err(_) => Err(rocket::http::Status::ServiceUnavailable),
-        },
-        Err(_) => Err(rocket::http::Status::ServiceUnavailable),
-    }
+/// Ollama model listing, served like `proxy_models`
+#[get("/api/tags")]
+async fn ollama_tags(
+    overrides: ProxyOverrides,
+    config: &State<SharedConfig>,
+) -> Result<Json<serde_json::Value>, OllamaError> {
+    let models = list_models(None, &overrides, config).await?;
+    let models = models
+        .get("data")
+        .and_then(|d| d.as_array())
+        .cloned()
+        .unwrap_or_default();
+    Ok(Json(ollama::native_tags(&models)))
 }
 
 #[derive(Deserialize)]
@@ -694,11 +1109,161 @@ struct Sorting {
     desc: bool,
 }
 
-#[get("/api/logs?<page>&<size>&<sort>")]
+/// Narrows down the listed logs, every given field has to match
+#[derive(FromForm, Default)]
+struct LogFilter {
+    provider_id: Option<String>,
+    model: Option<String>,
+    /// chat, completion or embedding
+    #[field(name = "type")]
+    request_type: Option<String>,
+    /// success, upstream_error, network_error, cancelled or pending for the requests that
+    /// haven't finished yet
+    outcome: Option<String>,
+    /// Start of the request time range, inclusive. Takes a date or date and time in UTC,
+    /// such as `2024-05-01`, `2024-05-01 12:30` or `2024-05-01T12:30:00.000Z`.
+    from: Option<String>,
+    /// End of the request time range, exclusive
+    to: Option<String>,
+    /// Bounds of the prompt and completion tokens together
+    min_tokens: Option<i64>,
+    max_tokens: Option<i64>,
+    min_speed: Option<i64>,
+    max_speed: Option<i64>,
+    /// Words that all have to appear in the request or response body
+    search: Option<String>,
+}
+
+/// A date or date and time in UTC, in the format SQLite stores timestamps in
+fn sql_timestamp(value: &str) -> Option<String> {
+    let value = value.trim();
+    let value = value.strip_suffix('Z').unwrap_or(value);
+    let (date, time) = value.split_once([' ', 'T']).unwrap_or((value, "00:00"));
+    // Fractions of a second are dropped
+    let time = match time.split_once('.') {
+        Some((time, fraction)) if fraction.bytes().all(|b| b.is_ascii_digit()) => time,
+        Some(_) => return None,
+        None => time,
+    };
+    let number = |part: &str| match part.len() {
+        1..=4 if part.bytes().all(|b| b.is_ascii_digit()) => part.parse::<u32>().ok(),
+        _ => None,
+    };
+    let date: Vec<u32> = date.split('-').map(number).collect::<Option<_>>()?;
+    let time: Vec<u32> = time.split(':').map(number).collect::<Option<_>>()?;
+    let [year, month, day] = date[..] else {
+        return None;
+    };
+    let (hour, minute, second) = match time[..] {
+        [hour, minute] => (hour, minute, 0),
+        [hour, minute, second] => (hour, minute, second),
+        _ => return None,
+    };
+    let days = match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if !(1..=12).contains(&month)
+        || !(1..=days).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return None;
+    }
+    Some(format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year, month, day, hour, minute, second
+    ))
+}
+
+impl LogFilter {
+    /// The WHERE clause for the filter and the values for its placeholders
+    fn to_sql(&self) -> Result<(String, Vec<rusqlite::types::Value>), Status> {
+        // Each condition with the value for its placeholder, if it has one
+        let mut conditions: Vec<(String, Option<rusqlite::types::Value>)> = Vec::new();
+        if let Some(ref provider_id) = self.provider_id {
+            conditions.push((
+                "provider_id = ?".to_string(),
+                Some(provider_id.clone().into()),
+            ));
+        }
+        if let Some(ref model) = self.model {
+            conditions.push(("model = ?".to_string(), Some(model.clone().into())));
+        }
+        if let Some(ref request_type) = self.request_type {
+            if !["chat", "completion", "embedding"].contains(&request_type.as_str()) {
+                return Err(Status::BadRequest);
+            }
+            conditions.push((
+                "request_type = ?".to_string(),
+                Some(request_type.clone().into()),
+            ));
+        }
+        match self.outcome.as_deref() {
+            None => {}
+            Some("pending") => conditions.push(("outcome IS NULL".to_string(), None)),
+            Some(o) if db::Outcome::ALL.iter().any(|v| v.as_str() == o) => {
+                conditions.push(("outcome = ?".to_string(), Some(o.to_string().into())))
+            }
+            Some(_) => return Err(Status::BadRequest),
+        }
+        // Timestamps are compared as text, one SQLite can't read would silently match
+        // nothing, so those are rejected
+        if let Some(ref from) = self.from {
+            let from = sql_timestamp(from).ok_or(Status::BadRequest)?;
+            conditions.push(("request_time >= ?".to_string(), Some(from.into())));
+        }
+        if let Some(ref to) = self.to {
+            let to = sql_timestamp(to).ok_or(Status::BadRequest)?;
+            conditions.push(("request_time < ?".to_string(), Some(to.into())));
+        }
+        let tokens = "COALESCE(prompt_tokens, 0) + COALESCE(completion_tokens, 0)";
+        if let Some(min_tokens) = self.min_tokens {
+            conditions.push((format!("{} >= ?", tokens), Some(min_tokens.into())));
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            conditions.push((format!("{} <= ?", tokens), Some(max_tokens.into())));
+        }
+        if let Some(min_speed) = self.min_speed {
+            conditions.push(("speed >= ?".to_string(), Some(min_speed.into())));
+        }
+        if let Some(max_speed) = self.max_speed {
+            conditions.push(("speed <= ?".to_string(), Some(max_speed.into())));
+        }
+        if let Some(ref search) = self.search {
+            // Every word is quoted, so the FTS5 query syntax can't break the search
+            let query = search
+                .split_whitespace()
+                .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+                .collect::<Vec<_>>()
+                .join(" ");
+            if !query.is_empty() {
+                conditions.push((
+                    "id IN (SELECT rowid FROM requests_fts WHERE requests_fts MATCH ?)".to_string(),
+                    Some(query.into()),
+                ));
+            }
+        }
+        if conditions.is_empty() {
+            return Ok((String::new(), Vec::new()));
+        }
+        let (conditions, params): (Vec<_>, Vec<_>) = conditions.into_iter().unzip();
+        Ok((
+            format!("WHERE {}", conditions.join(" AND ")),
+            params.into_iter().flatten().collect(),
+        ))
+    }
+}
+
+#[get("/api/logs?<page>&<size>&<sort>&<filter..>")]
 async fn get_logs(
     page: Option<String>,
     size: Option<String>,
     sort: Option<String>,
+    filter: LogFilter,
 ) -> Result<Json<serde_json::Value>, rocket::http::Status> {
     let mut sort = sort.map(|s| {
         let mut parts = s.split(',');
@@ -714,44 +1279,63 @@ async fn get_logs(
         "request_time",
         "response_time",
         "chat",
+        "request_type",
         "model",
         "speed",
+        "status",
+        "outcome",
+        "cost",
     ];
+    let (filter, mut filter_params) = filter.to_sql()?;
     if let Some(ref s) = sort {
         if !valid_columns.contains(&s.column.as_str()) {
             sort = None;
         }
     }
-    let db_lock = DB_CONNECTION.lock().await;
+    let db_lock = db::DB_CONNECTION.lock().await;
     let db = db_lock.as_ref().unwrap();
     let total_rows = db
-        .prepare("SELECT COUNT(*) FROM requests")
+        .prepare(&format!("SELECT COUNT(*) FROM requests {}", filter))
         .unwrap()
-        .query_row([], |row| row.get::<_, i64>(0))
+        .query_row(rusqlite::params_from_iter(&filter_params), |row| {
+            row.get::<_, i64>(0)
+        })
         .unwrap();
     let mut stmt = db
         .prepare(&format!(
-            "SELECT id, timestamp, provider_id, prompt_tokens, completion_tokens, request_time, response_time, chat, model, speed FROM requests ORDER BY {} {} LIMIT ?1 OFFSET ?2",
+            "SELECT id, timestamp, provider_id, prompt_tokens, completion_tokens, request_time, response_time, chat, model, speed, request_type, outcome, error, status, cost, cached_tokens, prompt_tokens_method, completion_tokens_method, image_parts, audio_parts, file_parts FROM requests {} ORDER BY {} {} LIMIT ? OFFSET ?",
+            filter,
             sort.as_ref().map_or("timestamp", |s| s.column.as_str()),
             sort.as_ref().map_or("DESC", |s| if s.desc { "DESC" } else { "ASC" })
         ))
         .unwrap();
     let page_size = size.map(|s| s.parse::<i64>().unwrap_or(10)).unwrap_or(10);
     let offset = page.map(|i| i.parse::<i64>().unwrap_or(0)).unwrap_or(0) * page_size;
-    let mut rows = stmt
-        .query_map(params![page_size, offset], |row| {
+    filter_params.extend([page_size.into(), offset.into()]);
+    let rows = stmt
+        .query_map(rusqlite::params_from_iter(&filter_params), |row| {
             let id: i64 = row.get(0)?;
             let provider_id: String = row.get(2)?;
             let prompt_tokens: Option<i64> = row.get(3)?;
             let completion_tokens: Option<i64> = row.get(4)?;
             let request_time: String = row.get(5)?;
             let response_time: Option<String> = row.get(6)?;
-            if response_time.is_none() {
-                return Err(rusqlite::Error::InvalidQuery);
-            }
             let chat: bool = row.get(7)?;
             let model: String = row.get(8)?;
             let speed: Option<i64> = row.get(9)?;
+            let request_type: String = row.get(10)?;
+            let outcome: Option<String> = row.get(11)?;
+            let error: Option<String> = row.get(12)?;
+            let status: Option<i64> = row.get(13)?;
+            let cost: Option<f64> = row.get(14)?;
+            let cached_tokens: Option<i64> = row.get(15)?;
+            let prompt_tokens_method: Option<String> = row.get(16)?;
+            let completion_tokens_method: Option<String> = row.get(17)?;
+            let media_parts = [
+                ("image_parts", row.get::<_, Option<i64>>(18)?),
+                ("audio_parts", row.get::<_, Option<i64>>(19)?),
+                ("file_parts", row.get::<_, Option<i64>>(20)?),
+            ];
             let mut answer = HashMap::from([
                 (
                     "id".to_string(),
@@ -763,6 +1347,11 @@ async fn get_logs(
                 ),
                 ("model".to_string(), serde_json::Value::String(model)),
                 ("chat".to_string(), serde_json::Value::Bool(chat)),
+                (
+                    "request_type".to_string(),
+                    serde_json::Value::String(request_type),
+                ),
+                ("outcome".to_string(), json!(outcome)),
                 (
                     "request_time".to_string(),
                     serde_json::Value::String(request_time),
@@ -790,12 +1379,38 @@ async fn get_logs(
                     serde_json::Value::Number(serde_json::Number::from(speed)),
                 );
             }
+            if let Some(status) = status {
+                answer.insert(
+                    "status".to_string(),
+                    serde_json::Value::Number(serde_json::Number::from(status)),
+                );
+            }
+            if let Some(error) = error {
+                answer.insert("error".to_string(), serde_json::Value::String(error));
+            }
+            if let Some(cached_tokens) = cached_tokens {
+                answer.insert("cached_tokens".to_string(), json!(cached_tokens));
+            }
+            if let Some(method) = prompt_tokens_method {
+                answer.insert("prompt_tokens_method".to_string(), json!(method));
+            }
+            if let Some(method) = completion_tokens_method {
+                answer.insert("completion_tokens_method".to_string(), json!(method));
+            }
+            for (name, count) in media_parts {
+                if let Some(count) = count {
+                    answer.insert(name.to_string(), json!(count));
+                }
+            }
+            if let Some(cost) = cost {
+                answer.insert("cost".to_string(), json!(cost));
+            }
             Ok(answer)
         })
         .unwrap();
 
     let mut logs = Vec::new();
-    while let Some(row) = rows.next() {
+    for row in rows {
         match row {
             Ok(row) => logs.push(row),
             Err(e) => {
@@ -812,13 +1427,57 @@ async fn get_logs(
     }))
 }
 
+/// Use of every provider with a budget in the current day and month
+#[get("/api/budgets")]
+async fn get_budgets(config: &State<SharedConfig>) -> Json<Vec<budget::BudgetStatus>> {
+    let config = config.snapshot().await;
+    let mut budgets = Vec::new();
+    for provider in &config.providers {
+        if let Some(status) = budget::status(provider).await {
+            budgets.push(status);
+        }
+    }
+    Json(budgets)
+}
+
+/// Computes the cost of every logged request again with the current prices
+#[post("/api/logs/recompute-costs")]
+async fn recompute_costs(
+    config: &State<SharedConfig>,
+) -> Result<Json<serde_json::Value>, rocket::http::Status> {
+    let config = config.snapshot().await;
+    let updated = db::recompute_costs(&config).await.map_err(|e| {
+        error!("Failed to recompute the costs: {}", e);
+        Status::InternalServerError
+    })?;
+    Ok(Json(json!({ "updated": updated })))
+}
+
+/// Request counts, tokens, speed, error rate and latency of the requests matching the
+/// filter, grouped by provider, model, day or hour
+#[get("/api/stats/<group>?<filter..>")]
+async fn get_stats(
+    group: &str,
+    filter: LogFilter,
+) -> Result<Json<serde_json::Value>, rocket::http::Status> {
+    let grouping = stats::Grouping::parse(group).ok_or(Status::NotFound)?;
+    let (filter, params) = filter.to_sql()?;
+    let db_lock = db::DB_CONNECTION.lock().await;
+    let db = db_lock.as_ref().unwrap();
+    let stats = stats::aggregate(db, grouping, &filter, &params).map_err(|e| {
+        error!("Failed to aggregate the requests: {}", e);
+        Status::InternalServerError
+    })?;
+    Ok(Json(json!({ "group": group, "stats": stats })))
+}
+
 #[get("/api/logs/<id>")]
 async fn get_log(id: i64) -> Result<Json<serde_json::Value>, rocket::http::Status> {
-    let db_lock = DB_CONNECTION.lock().await;
+    let db_lock = db::DB_CONNECTION.lock().await;
     let db = db_lock.as_ref().unwrap();
     let mut stmt = db
         .prepare(
-            "SELECT id, timestamp, provider_id, chat, prompt_tokens, completion_tokens, request, response, request_time, response_time FROM requests WHERE id = ?1",
+            "SELECT id, timestamp, provider_id, chat, prompt_tokens, completion_tokens, request, response, request_time, response_time, request_type, outcome, error, status, cost, cached_tokens, prompt_tokens_method, completion_tokens_method, image_parts, audio_parts, file_parts FROM requests WHERE id = ?1",
         )
         .unwrap();
 
@@ -832,6 +1491,19 @@ async fn get_log(id: i64) -> Result<Json<serde_json::Value>, rocket::http::Statu
         let response: Option<String> = row.get(7)?;
         let request_time: String = row.get(8)?;
         let response_time: Option<String> = row.get(9)?;
+        let request_type: String = row.get(10)?;
+        let outcome: Option<String> = row.get(11)?;
+        let error: Option<String> = row.get(12)?;
+        let status: Option<i64> = row.get(13)?;
+        let cost: Option<f64> = row.get(14)?;
+        let cached_tokens: Option<i64> = row.get(15)?;
+        let prompt_tokens_method: Option<String> = row.get(16)?;
+        let completion_tokens_method: Option<String> = row.get(17)?;
+        let media_parts = [
+            ("image_parts", row.get::<_, Option<i64>>(18)?),
+            ("audio_parts", row.get::<_, Option<i64>>(19)?),
+            ("file_parts", row.get::<_, Option<i64>>(20)?),
+        ];
         let request_data: RequestFormat =
             serde_json::from_str(&request).map_err(|_| rusqlite::Error::InvalidQuery)?;
         let mut answer = HashMap::from([
@@ -844,6 +1516,11 @@ async fn get_log(id: i64) -> Result<Json<serde_json::Value>, rocket::http::Statu
                 serde_json::Value::String(provider_id),
             ),
             ("chat".to_string(), serde_json::Value::Bool(chat)),
+            (
+                "request_type".to_string(),
+                serde_json::Value::String(request_type),
+            ),
+            ("outcome".to_string(), json!(outcome)),
             (
                 "model".to_string(),
                 serde_json::Value::String(request_data.model),
@@ -873,12 +1550,40 @@ async fn get_log(id: i64) -> Result<Json<serde_json::Value>, rocket::http::Statu
                 serde_json::Value::Number(serde_json::Number::from(completion_tokens)),
             );
         }
-        if let Some(response) = response {
+        if let Some(status) = status {
             answer.insert(
-                "response".to_string(),
-                serde_json::from_str(&response).unwrap(),
+                "status".to_string(),
+                serde_json::Value::Number(serde_json::Number::from(status)),
             );
         }
+        if let Some(error) = error {
+            answer.insert("error".to_string(), serde_json::Value::String(error));
+        }
+        if let Some(cached_tokens) = cached_tokens {
+            answer.insert("cached_tokens".to_string(), json!(cached_tokens));
+        }
+        if let Some(method) = prompt_tokens_method {
+            answer.insert("prompt_tokens_method".to_string(), json!(method));
+        }
+        if let Some(method) = completion_tokens_method {
+            answer.insert("completion_tokens_method".to_string(), json!(method));
+        }
+        for (name, count) in media_parts {
+            if let Some(count) = count {
+                answer.insert(name.to_string(), json!(count));
+            }
+        }
+        if let Some(cost) = cost {
+            answer.insert("cost".to_string(), json!(cost));
+        }
+        if let Some(response) = response {
+            // Upstream errors aren't always JSON, those are returned as a string
+            let response = match serde_json::from_str(&response) {
+                Ok(response) => response,
+                Err(_) => serde_json::Value::String(response),
+            };
+            answer.insert("response".to_string(), response);
+        }
         Ok(answer)
     });
 
@@ -902,26 +1607,51 @@ async fn get_active_provider(config: &State<SharedConfig>) -> Json<Option<String
     Json(config.provider.clone())
 }
 
+type MessageResponse = Result<Json<HashMap<String, String>>, Custom<Json<HashMap<String, String>>>>;
+
+fn message(message: &str) -> Json<HashMap<String, String>> {
+    Json(HashMap::from([(
+        "message".to_string(),
+        message.to_string(),
+    )]))
+}
+
+/// Persists `updated` to disk and only swaps it into `config` once the write succeeded,
+/// so the in-memory config never diverges from the file
+fn commit_config(
+    config: &mut AppConfig,
+    updated: AppConfig,
+) -> Result<(), Custom<Json<HashMap<String, String>>>> {
+    if let Err(e) = updated.validate() {
+        return Err(Custom(
+            Status::BadRequest,
+            message(&format!("Invalid configuration: {}", e)),
+        ));
+    }
+    if let Err(e) = updated.save() {
+        error!("Failed to save configuration: {}", e);
+        return Err(Custom(
+            Status::InternalServerError,
+            message(&format!("Failed to save configuration: {}", e)),
+        ));
+    }
+    *config = updated;
+    Ok(())
+}
+
 #[post("/api/config/active-provider", data = "<provider_id>")]
-async fn set_active_provider(
-    provider_id: String,
-    config: &State<SharedConfig>,
-) -> Json<HashMap<String, String>> {
+async fn set_active_provider(provider_id: String, config: &State<SharedConfig>) -> MessageResponse {
     let mut config = config.lock().await;
+    let mut updated = config.clone();
     if provider_id.is_empty() {
-        config.provider = None;
-    } else if config.providers.iter().any(|p| p.id == provider_id) {
-        config.provider = Some(provider_id.clone());
+        updated.provider = None;
+    } else if updated.providers.iter().any(|p| p.id == provider_id) {
+        updated.provider = Some(provider_id.clone());
     } else {
-        return Json(HashMap::from([(
-            "message".to_string(),
-            "Service not found".to_string(),
-        )]));
+        return Ok(message("Service not found"));
     }
-    Json(HashMap::from([(
-        "message".to_string(),
-        "Service updated successfully".to_string(),
-    )]))
+    commit_config(&mut config, updated)?;
+    Ok(message("Service updated successfully"))
 }
 
 #[post("/api/config/providers/<provider_id>", data = "<new_provider>")]
@@ -929,19 +1659,15 @@ async fn add_provider(
     provider_id: String,
     new_provider: Json<ProviderConfig>,
     config: &State<SharedConfig>,
-) -> Json<HashMap<String, String>> {
+) -> MessageResponse {
     let mut config = config.lock().await;
     if config.providers.iter().any(|p| p.id == provider_id) {
-        return Json(HashMap::from([(
-            "message".to_string(),
-            "Service already exists".to_string(),
-        )]));
+        return Ok(message("Service already exists"));
     }
-    config.providers.push(new_provider.into_inner());
-    Json(HashMap::from([(
-        "message".to_string(),
-        "Service added successfully".to_string(),
-    )]))
+    let mut updated = config.clone();
+    updated.providers.push(new_provider.into_inner());
+    commit_config(&mut config, updated)?;
+    Ok(message("Service added successfully"))
 }
 
 #[put("/api/config/providers/<provider_id>", data = "<updated_provider>")]
@@ -949,9 +1675,10 @@ async fn update_provider(
     provider_id: String,
     updated_provider: Json<HashMap<String, serde_json::Value>>,
     config: &State<SharedConfig>,
-) -> Json<HashMap<String, String>> {
+) -> MessageResponse {
     let mut config = config.lock().await;
-    if let Some(provider) = config.providers.iter_mut().find(|p| p.id == provider_id) {
+    let mut updated = config.clone();
+    if let Some(provider) = updated.providers.iter_mut().find(|p| p.id == provider_id) {
         let updated_provider = updated_provider.into_inner();
         for (key, value) in updated_provider {
             match key.as_str() {
@@ -962,38 +1689,150 @@ async fn update_provider(
             };
         }
     } else {
-        return Json(HashMap::from([(
-            "message".to_string(),
-            "Service not found".to_string(),
-        )]));
+        return Ok(message("Service not found"));
     }
-    Json(HashMap::from([(
-        "message".to_string(),
-        "Service updated successfully".to_string(),
-    )]))
+    commit_config(&mut config, updated)?;
+    Ok(message("Service updated successfully"))
 }
 
-#[delete("/api/config/providers/<provider_id>")]
-async fn delete_provider(
+/// Sets the budget of a provider, `null` removes it
+#[post("/api/config/providers/<provider_id>/budget", data = "<budget>")]
+async fn set_budget(
     provider_id: String,
+    budget: Json<Option<Budget>>,
     config: &State<SharedConfig>,
-) -> Json<HashMap<String, String>> {
+) -> MessageResponse {
     let mut config = config.lock().await;
-    if let Some(index) = config.providers.iter().position(|p| p.id == provider_id) {
-        if config.provider == Some(provider_id) {
-            config.provider = None;
+    let mut updated = config.clone();
+    let Some(provider) = updated.providers.iter_mut().find(|p| p.id == provider_id) else {
+        return Ok(message("Provider not found"));
+    };
+    provider.budget = budget.into_inner();
+    commit_config(&mut config, updated)?;
+    Ok(message("Budget updated successfully"))
+}
+
+#[delete("/api/config/providers/<provider_id>")]
+async fn delete_provider(provider_id: String, config: &State<SharedConfig>) -> MessageResponse {
+    let mut config = config.lock().await;
+    let mut updated = config.clone();
+    if let Some(index) = updated.providers.iter().position(|p| p.id == provider_id) {
+        updated.fallback.retain(|id| *id != provider_id);
+        updated.pricing.shift_remove(&provider_id);
+        updated
+            .aliases
+            .retain(|_, alias| alias.provider != provider_id);
+        for pool in updated.providers.iter_mut().filter(|p| p.id != provider_id) {
+            let Some(members) = pool.pool.as_mut().map(|p| &mut p.members) else {
+                continue;
+            };
+            members.retain(|m| m.provider != provider_id);
+            if !members.iter().any(|m| m.weight > 0) {
+                return Err(Custom(
+                    Status::BadRequest,
+                    message(&format!(
+                        "Provider '{}' is the last active member of pool '{}', change or delete the pool first",
+                        provider_id, pool.id
+                    )),
+                ));
+            }
+        }
+        for budget in updated
+            .providers
+            .iter_mut()
+            .filter_map(|p| p.budget.as_mut())
+        {
+            if budget.fallback.as_ref() == Some(&provider_id) {
+                budget.fallback = None;
+            }
         }
-        config.providers.remove(index);
-        Json(HashMap::from([(
-            "message".to_string(),
-            "Service deleted successfully".to_string(),
-        )]))
+        if updated.provider == Some(provider_id) {
+            updated.provider = None;
+        }
+        updated.providers.remove(index);
+        commit_config(&mut config, updated)?;
+        Ok(message("Service deleted successfully"))
     } else {
-        Json(HashMap::from([(
-            "message".to_string(),
-            "Provider not found".to_string(),
-        )]))
+        Ok(message("Provider not found"))
+    }
+}
+
+#[get("/api/config/fallback")]
+async fn get_fallback(config: &State<SharedConfig>) -> Json<Vec<String>> {
+    let config = config.lock().await;
+    Json(config.fallback.clone())
+}
+
+#[post("/api/config/fallback", data = "<fallback>")]
+async fn set_fallback(
+    fallback: Json<Vec<String>>,
+    config: &State<SharedConfig>,
+) -> MessageResponse {
+    let mut config = config.lock().await;
+    let fallback = fallback.into_inner();
+    if let Some(id) = fallback
+        .iter()
+        .find(|id| !config.providers.iter().any(|p| &p.id == *id))
+    {
+        return Ok(message(&format!("Provider {} not found", id)));
+    }
+    let mut updated = config.clone();
+    updated.fallback = fallback;
+    commit_config(&mut config, updated)?;
+    Ok(message("Fallback updated successfully"))
+}
+
+#[get("/api/config/pricing")]
+async fn get_pricing(
+    config: &State<SharedConfig>,
+) -> Json<IndexMap<String, IndexMap<String, ModelPrice>>> {
+    let config = config.lock().await;
+    Json(config.pricing.clone())
+}
+
+/// Replaces the prices of a provider. Requests already logged keep their cost until
+/// `/api/logs/recompute-costs` is called.
+#[post("/api/config/pricing/<provider_id>", data = "<prices>")]
+async fn set_pricing(
+    provider_id: String,
+    prices: Json<IndexMap<String, ModelPrice>>,
+    config: &State<SharedConfig>,
+) -> MessageResponse {
+    let mut config = config.lock().await;
+    let mut updated = config.clone();
+    updated.pricing.insert(provider_id, prices.into_inner());
+    commit_config(&mut config, updated)?;
+    Ok(message("Pricing updated successfully"))
+}
+
+#[get("/api/config/aliases")]
+async fn get_aliases(config: &State<SharedConfig>) -> Json<IndexMap<String, ModelAlias>> {
+    let config = config.lock().await;
+    Json(config.aliases.clone())
+}
+
+#[post("/api/config/aliases/<name>", data = "<alias>")]
+async fn set_alias(
+    name: String,
+    alias: Json<ModelAlias>,
+    config: &State<SharedConfig>,
+) -> MessageResponse {
+    let mut config = config.lock().await;
+    let mut updated = config.clone();
+    updated.aliases.insert(name, alias.into_inner());
+    commit_config(&mut config, updated)?;
+    Ok(message("Alias updated successfully"))
+}
+
+#[delete("/api/config/aliases/<name>")]
+async fn delete_alias(name: String, config: &State<SharedConfig>) -> MessageResponse {
+    let mut config = config.lock().await;
+    let mut updated = config.clone();
+    if updated.aliases.shift_remove(&name).is_none() {
+        return Ok(message("Alias not found"));
     }
+    commit_config(&mut config, updated)?;
+    Ok(message("Alias deleted successfully"))
 }
 
 #[post(
@@ -1004,32 +1843,23 @@ async fn set_active_preset(
     provider_id: String,
     preset_id: String,
     config: &State<SharedConfig>,
-) -> Json<HashMap<String, String>> {
+) -> MessageResponse {
     let mut config = config.lock().await;
-    if let Some(provider) = config.providers.iter_mut().find(|p| p.id == provider_id) {
+    let mut updated = config.clone();
+    if let Some(provider) = updated.providers.iter_mut().find(|p| p.id == provider_id) {
         if preset_id.is_empty() {
             provider.preset = None;
-            Json(HashMap::from([(
-                "message".to_string(),
-                "Preset removed successfully".to_string(),
-            )]))
+            commit_config(&mut config, updated)?;
+            Ok(message("Preset removed successfully"))
         } else if provider.presets.iter().any(|p| p.id == preset_id) {
             provider.preset = Some(preset_id);
-            Json(HashMap::from([(
-                "message".to_string(),
-                "Preset updated successfully".to_string(),
-            )]))
+            commit_config(&mut config, updated)?;
+            Ok(message("Preset updated successfully"))
         } else {
-            Json(HashMap::from([(
-                "message".to_string(),
-                "Preset not found".to_string(),
-            )]))
+            Ok(message("Preset not found"))
         }
     } else {
-        Json(HashMap::from([(
-            "message".to_string(),
-            "Provider not found".to_string(),
-        )]))
+        Ok(message("Provider not found"))
     }
 }
 
@@ -1038,19 +1868,15 @@ async fn add_preset(
     provider_id: String,
     new_preset: Json<Preset>,
     config: &State<SharedConfig>,
-) -> Json<HashMap<String, String>> {
+) -> MessageResponse {
     let mut config = config.lock().await;
-    if let Some(provider) = config.providers.iter_mut().find(|p| p.id == provider_id) {
+    let mut updated = config.clone();
+    if let Some(provider) = updated.providers.iter_mut().find(|p| p.id == provider_id) {
         provider.presets.push(new_preset.into_inner());
-        Json(HashMap::from([(
-            "message".to_string(),
-            "Preset added successfully".to_string(),
-        )]))
+        commit_config(&mut config, updated)?;
+        Ok(message("Preset added successfully"))
     } else {
-        Json(HashMap::from([(
-            "message".to_string(),
-            "Provider not found".to_string(),
-        )]))
+        Ok(message("Provider not found"))
     }
 }
 
@@ -1063,9 +1889,10 @@ async fn update_preset(
     preset_id: String,
     updated_preset: Json<HashMap<String, serde_json::Value>>,
     config: &State<SharedConfig>,
-) -> Json<HashMap<String, String>> {
+) -> MessageResponse {
     let mut config = config.lock().await;
-    if let Some(provider) = config.providers.iter_mut().find(|p| p.id == provider_id) {
+    let mut updated = config.clone();
+    if let Some(provider) = updated.providers.iter_mut().find(|p| p.id == provider_id) {
         if let Some(preset) = provider.presets.iter_mut().find(|p| p.id == preset_id) {
             let updated_preset = updated_preset.into_inner();
             for (key, value) in updated_preset {
@@ -1079,21 +1906,13 @@ async fn update_preset(
                     _ => None,
                 };
             }
-            Json(HashMap::from([(
-                "message".to_string(),
-                "Preset updated successfully".to_string(),
-            )]))
+            commit_config(&mut config, updated)?;
+            Ok(message("Preset updated successfully"))
         } else {
-            Json(HashMap::from([(
-                "message".to_string(),
-                "Preset not found".to_string(),
-            )]))
+            Ok(message("Preset not found"))
         }
     } else {
-        Json(HashMap::from([(
-            "message".to_string(),
-            "Provider not found".to_string(),
-        )]))
+        Ok(message("Provider not found"))
     }
 }
 
@@ -1116,43 +1935,29 @@ async fn index(file: PathBuf) -> Option<NamedFile> {
 
 #[launch]
 async fn rocket() -> _ {
-    let config_path = dirs::config_dir().unwrap().join("aiswitch");
-    std::fs::create_dir_all(&config_path).unwrap();
-    let config_path = config_path.join("config.json");
-    let config = match AppConfig::load_from_file(config_path) {
+    let config_path = AppConfig::path();
+    std::fs::create_dir_all(config_path.parent().unwrap()).unwrap();
+    let config = match AppConfig::load_from_file(&config_path) {
         Ok(config) => config,
         Err(_) => {
             let db_path = dirs::data_dir().unwrap().join("aiswitch").join("db.sqlite");
-            let config = AppConfig {
-                db_path: db_path,
+            AppConfig {
+                db_path,
                 ..Default::default()
-            };
-            config
+            }
         }
     };
+    // Starting with a default config instead would overwrite the file on the next save
+    if let Err(e) = config.validate() {
+        panic!("Invalid configuration in {}: {}", config_path.display(), e);
+    }
     let db_path = config.db_path.clone();
-    let config = Arc::new(Mutex::new(config));
-
-    let conn = Connection::open(db_path).unwrap();
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS requests (
-            id INTEGER PRIMARY KEY,
-            timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-            provider_id TEXT NOT NULL,
-            chat BOOLEAN DEFAULT FALSE,
-            request TEXT NOT NULL,
-            response TEXT,
-            request_time TIMESTAMP NOT NULL,
-            response_time TIMESTAMP,
-            prompt_tokens INTEGER,
-            completion_tokens INTEGER,
-            model TEXT NOT NULL,
-            SPEED INTEGER
-        )",
-        [],
-    )
-    .unwrap();
-    DB_CONNECTION.lock().await.replace(conn);
+    let config = SharedConfig::new(config);
+    config::watch_file(config.clone(), config_path);
+
+    if let Err(e) = db::open(&db_path).await {
+        panic!("Failed to open the database {}: {}", db_path.display(), e);
+    }
 
     let allowed_origins = AllowedOrigins::all();
     let cors = rocket_cors::CorsOptions {
@@ -1173,20 +1978,122 @@ async fn rocket() -> _ {
             index,
             proxy_completions,
             proxy_chat_completions,
+            proxy_embeddings,
+            proxy_messages,
             proxy_models,
+            proxy_tokenize,
+            proxy_detokenize,
+            ollama_chat,
+            ollama_generate,
+            ollama_tags,
             get_providers,
             get_active_provider,
             set_active_provider,
             add_provider,
             update_provider,
             delete_provider,
+            get_fallback,
+            set_fallback,
+            get_aliases,
+            set_alias,
+            delete_alias,
             set_active_preset,
             add_preset,
             update_preset,
             get_logs,
             get_log,
+            get_stats,
+            recompute_costs,
+            get_budgets,
+            set_budget,
+            get_pricing,
+            set_pricing,
             get_config,
         ],
     )
     .attach(cors)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_log_filter_timestamps() {
+        assert_eq!(
+            sql_timestamp("2024-05-01").as_deref(),
+            Some("2024-05-01 00:00:00")
+        );
+        assert_eq!(
+            sql_timestamp("2024-05-01 12:30").as_deref(),
+            Some("2024-05-01 12:30:00")
+        );
+        assert_eq!(
+            sql_timestamp("2024-5-1T08:05:09Z").as_deref(),
+            Some("2024-05-01 08:05:09")
+        );
+        assert_eq!(
+            sql_timestamp("2024-05-01T08:05:09.123Z").as_deref(),
+            Some("2024-05-01 08:05:09")
+        );
+        for invalid in [
+            "",
+            "yesterday",
+            "2024-13-01",
+            "2024-05-32",
+            "2024-05-01 24:00",
+            "2024-05-01 12",
+            "2024-05-01 12:00:00:00",
+            "2024-05-01T+1:00",
+            "2024-05-01T12:00:00.1a",
+        ] {
+            assert_eq!(sql_timestamp(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn checks_the_days_of_each_month() {
+        for valid in [
+            "2024-01-31",
+            "2024-02-29",
+            "2000-02-29",
+            "2024-04-30",
+            "2024-12-31",
+        ] {
+            assert!(sql_timestamp(valid).is_some(), "{}", valid);
+        }
+        for invalid in [
+            "2024-02-31",
+            "2023-02-29",
+            "1900-02-29",
+            "2024-04-31",
+            "2024-11-31",
+        ] {
+            assert_eq!(sql_timestamp(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn rejects_log_filters_with_invalid_timestamps() {
+        let filter = LogFilter {
+            from: Some("2024-05-01".to_string()),
+            to: Some("2024-06-01 00:00".to_string()),
+            ..Default::default()
+        };
+        let (clause, params) = filter.to_sql().unwrap();
+        assert_eq!(clause, "WHERE request_time >= ? AND request_time < ?");
+        assert_eq!(
+            params,
+            [
+                rusqlite::types::Value::from("2024-05-01 00:00:00".to_string()),
+                rusqlite::types::Value::from("2024-06-01 00:00:00".to_string()),
+            ]
+        );
+
+        let filter = LogFilter {
+            to: Some("last week".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(filter.to_sql().unwrap_err(), Status::BadRequest);
+    }
+}
@@ -0,0 +1,134 @@
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use log::warn;
+use reqwest::Client;
+use rocket::tokio::sync::Mutex;
+use serde_json::json;
+
+use crate::config::{AppConfig, ProviderConfig, ProviderKind};
+use crate::ollama;
+use crate::proxy::authorize;
+
+struct CachedModels {
+    /// Providers the list was built from, a config change invalidates the cache
+    providers: String,
+    fetched: Instant,
+    models: Vec<serde_json::Value>,
+}
+
+static AGGREGATE_CACHE: LazyLock<Mutex<Option<CachedModels>>> = LazyLock::new(|| Mutex::new(None));
+
+/// Fetches the `/models` listing of a provider, or `/api/tags` for Ollama. When the
+/// provider's active preset pins a model, only that model is kept.
+pub async fn fetch_models(
+    client: &Client,
+    provider: &ProviderConfig,
+) -> Result<serde_json::Map<String, serde_json::Value>, reqwest::Error> {
+    let api_url = match provider.kind {
+        ProviderKind::Ollama => format!("{}/api/tags", provider.api_url),
+        _ => format!("{}/models", provider.api_url),
+    };
+    let text = authorize(provider, client.get(&api_url))
+        .send()
+        .await?
+        .text()
+        .await?;
+
+    let mut response: serde_json::Map<_, _> = match provider.kind {
+        ProviderKind::Ollama => serde_json::from_str(&text)
+            .map(|tags| ollama::openai_models(&tags))
+            .unwrap_or_default(),
+        _ => serde_json::from_str(&text).unwrap_or_default(),
+    };
+    if let Some(models) = response.get_mut("data").and_then(|m| m.as_array_mut()) {
+        if let Some(override_model) = provider.preset.as_ref().and_then(|p| {
+            provider
+                .presets
+                .iter()
+                .find(|preset| &preset.id == p)
+                .and_then(|preset| preset.overrides.get("model").and_then(|m| m.as_str()))
+        }) {
+            models.retain(|m| {
+                m.as_object()
+                    .and_then(|m| m.get("id").and_then(|id| id.as_str()))
+                    .is_none_or(|id| id == override_model)
+            });
+        }
+    }
+    Ok(response)
+}
+
+/// Lists the models of every provider concurrently, with ids prefixed by the provider id
+/// so they can be used for routing. Providers that fail or don't answer within the
+/// configured timeout are left out. The result is cached for `cache_ttl_secs`.
+pub async fn aggregate_models(config: &AppConfig) -> Vec<serde_json::Value> {
+    let fingerprint = serde_json::to_string(&config.providers).unwrap_or_default();
+    let ttl = Duration::from_secs(config.models.cache_ttl_secs);
+    let mut cache = AGGREGATE_CACHE.lock().await;
+    if let Some(cached) = cache.as_ref() {
+        if cached.providers == fingerprint && cached.fetched.elapsed() < ttl {
+            return cached.models.clone();
+        }
+    }
+
+    let client = Client::new();
+    let timeout = Duration::from_millis(config.models.timeout_ms);
+    let handles: Vec<_> = config
+        .providers
+        .iter()
+        // Pools only serve the models of their members, which are listed already
+        .filter(|p| p.pool.is_none())
+        .cloned()
+        .map(|provider| {
+            let client = client.clone();
+            rocket::tokio::spawn(async move {
+                let result =
+                    rocket::tokio::time::timeout(timeout, fetch_models(&client, &provider)).await;
+                (provider, result)
+            })
+        })
+        .collect();
+
+    let mut models = Vec::new();
+    for handle in handles {
+        let Ok((provider, result)) = handle.await else {
+            continue;
+        };
+        let response = match result {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => {
+                warn!("Skipping models of provider '{}': {}", provider.id, e);
+                continue;
+            }
+            Err(_) => {
+                warn!("Skipping models of provider '{}': timed out", provider.id);
+                continue;
+            }
+        };
+        let Some(data) = response.get("data").and_then(|d| d.as_array()) else {
+            continue;
+        };
+        for model in data {
+            let mut model = model.clone();
+            if let Some(model) = model.as_object_mut() {
+                let Some(id) = model.get("id").and_then(|id| id.as_str()) else {
+                    continue;
+                };
+                let id = format!("{}/{}", provider.id, id);
+                model.insert("id".to_string(), json!(id));
+                model
+                    .entry("owned_by")
+                    .or_insert_with(|| json!(provider.id));
+            }
+            models.push(model);
+        }
+    }
+
+    cache.replace(CachedModels {
+        providers: fingerprint,
+        fetched: Instant::now(),
+        models: models.clone(),
+    });
+    models
+}
@@ -0,0 +1,1036 @@
+//! Translation between the OpenAI chat/text completions API and Ollama's native
+//! `/api/chat` and `/api/generate` API, in both directions. Ollama streams newline
+//! delimited JSON rather than SSE.
+
+use std::collections::{HashMap, VecDeque};
+
+use serde_json::{json, Map, Value};
+
+use crate::chat::{now, text_of};
+use crate::proxy::RequestKind;
+use crate::sse;
+
+/// OpenAI request fields and the Ollama `options` they correspond to
+const OPTIONS: [(&str, &str); 8] = [
+    ("temperature", "temperature"),
+    ("top_p", "top_p"),
+    ("top_k", "top_k"),
+    ("seed", "seed"),
+    ("stop", "stop"),
+    ("frequency_penalty", "frequency_penalty"),
+    ("presence_penalty", "presence_penalty"),
+    ("max_tokens", "num_predict"),
+];
+
+/// Tool calls of a streamed response beyond this index are dropped, the index comes from
+/// the upstream and sizes a buffer
+const MAX_TOOL_CALLS: usize = 128;
+
+/// RFC 3339 timestamp of a Unix time, as Ollama uses for `created_at`
+fn timestamp(secs: u64) -> String {
+    // Civil date from days since the epoch, see
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = (secs / 86_400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let time = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3_600,
+        time % 3_600 / 60,
+        time % 60
+    )
+}
+
+/// Guesses the media type of a base64 encoded image from its first bytes, as Ollama
+/// sends images without one
+fn media_type(data: &str) -> &'static str {
+    match data.get(..4) {
+        Some("iVBO") => "image/png",
+        Some("R0lG") => "image/gif",
+        Some("UklG") => "image/webp",
+        _ => "image/jpeg",
+    }
+}
+
+/// Ollama messages for a list of OpenAI messages. Only inline (data URL) images can be
+/// passed on, Ollama doesn't fetch remote ones.
+fn native_messages(messages: Option<&Value>) -> Vec<Value> {
+    // Ollama identifies tool results by function name rather than by call id
+    let mut tool_names = HashMap::new();
+    let mut native = Vec::new();
+    for message in messages.and_then(|m| m.as_array()).into_iter().flatten() {
+        let mut reply = json!({
+            "role": message["role"],
+            "content": text_of(&message["content"]),
+        });
+
+        let images: Vec<&str> = message["content"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|p| p["image_url"]["url"].as_str())
+            .filter_map(|url| url.split_once(";base64,").map(|(_, data)| data))
+            .collect();
+        if !images.is_empty() {
+            reply["images"] = json!(images);
+        }
+
+        if let Some(calls) = message["tool_calls"].as_array() {
+            let calls: Vec<Value> = calls
+                .iter()
+                .map(|call| {
+                    let function = &call["function"];
+                    if let Some(id) = call["id"].as_str() {
+                        tool_names.insert(id.to_owned(), function["name"].clone());
+                    }
+                    let arguments = function["arguments"]
+                        .as_str()
+                        .and_then(|a| serde_json::from_str::<Value>(a).ok())
+                        .unwrap_or_else(|| json!({}));
+                    json!({ "function": { "name": function["name"], "arguments": arguments } })
+                })
+                .collect();
+            reply["tool_calls"] = json!(calls);
+        }
+
+        if let Some(name) = message["tool_call_id"]
+            .as_str()
+            .and_then(|id| tool_names.get(id))
+        {
+            reply["tool_name"] = name.clone();
+        }
+        native.push(reply);
+    }
+    native
+}
+
+/// Converts an OpenAI request of `kind` into a request for Ollama's `/api/chat`,
+/// `/api/generate` or `/api/embed`. Fails for prompts Ollama can't take.
+pub fn native_request(kind: RequestKind, body: &HashMap<String, Value>) -> Result<Value, String> {
+    let mut request = json!({
+        "model": body.get("model").cloned().unwrap_or(Value::Null),
+        // Ollama streams unless told otherwise
+        "stream": body.get("stream").and_then(|s| s.as_bool()).unwrap_or(false),
+    });
+    match kind {
+        RequestKind::Chat => {
+            request["messages"] = json!(native_messages(body.get("messages")));
+            if let Some(tools) = body.get("tools") {
+                request["tools"] = tools.clone();
+            }
+        }
+        RequestKind::Completion => {
+            // Ollama takes a single prompt, a batch is sent as one. Token prompts can't be
+            // passed on.
+            request["prompt"] = match body.get("prompt") {
+                Some(Value::Array(prompts)) => {
+                    let prompts: Option<Vec<&str>> = prompts.iter().map(|p| p.as_str()).collect();
+                    match prompts {
+                        Some(prompts) => json!(prompts.join("\n")),
+                        None => return Err("Ollama doesn't take token prompts".to_string()),
+                    }
+                }
+                Some(prompt) => prompt.clone(),
+                None => json!(""),
+            };
+            if let Some(suffix) = body.get("suffix") {
+                request["suffix"] = suffix.clone();
+            }
+        }
+        RequestKind::Embedding => {
+            request["input"] = body.get("input").cloned().unwrap_or(json!(""));
+        }
+    }
+
+    // Native options set by a preset are kept
+    let mut options = body
+        .get("options")
+        .and_then(|o| o.as_object())
+        .cloned()
+        .unwrap_or_default();
+    for (openai, native) in OPTIONS {
+        if let Some(value) = body.get(openai) {
+            let value = match value {
+                Value::String(stop) if openai == "stop" => json!([stop]),
+                value => value.clone(),
+            };
+            options.insert(native.to_string(), value);
+        }
+    }
+    if let Some(max_tokens) = body.get("max_completion_tokens") {
+        options.insert("num_predict".to_string(), max_tokens.clone());
+    }
+    if !options.is_empty() {
+        request["options"] = json!(options);
+    }
+
+    if let Some(format) = body.get("response_format") {
+        match format["type"].as_str() {
+            Some("json_object") => request["format"] = json!("json"),
+            Some("json_schema") => request["format"] = format["json_schema"]["schema"].clone(),
+            _ => {}
+        }
+    }
+    if let Some(keep_alive) = body.get("keep_alive") {
+        request["keep_alive"] = keep_alive.clone();
+    }
+    Ok(request)
+}
+
+/// OpenAI tool calls for the tool calls of an Ollama message, numbered from `first`
+fn openai_tool_calls(message: &Value, first: usize) -> Vec<Value> {
+    message["tool_calls"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .enumerate()
+        .map(|(i, call)| {
+            let function = &call["function"];
+            let arguments = match &function["arguments"] {
+                Value::String(arguments) => arguments.clone(),
+                arguments => arguments.to_string(),
+            };
+            json!({
+                "index": first + i,
+                "id": format!("call_{}", first + i),
+                "type": "function",
+                "function": { "name": function["name"], "arguments": arguments },
+            })
+        })
+        .collect()
+}
+
+fn finish_reason(done_reason: &Value, tool_calls: bool) -> &'static str {
+    if tool_calls {
+        return "tool_calls";
+    }
+    match done_reason.as_str() {
+        Some("length") => "length",
+        _ => "stop",
+    }
+}
+
+/// OpenAI usage for the token counts of a finished Ollama response
+fn openai_usage(response: &Value) -> Value {
+    let prompt_tokens = response["prompt_eval_count"].as_u64().unwrap_or(0);
+    let completion_tokens = response["eval_count"].as_u64().unwrap_or(0);
+    json!({
+        "prompt_tokens": prompt_tokens,
+        "completion_tokens": completion_tokens,
+        "total_tokens": prompt_tokens + completion_tokens,
+    })
+}
+
+/// Converts an Ollama error body into the OpenAI error format
+pub fn openai_error(body: &Value) -> Value {
+    json!({
+        "error": {
+            "message": body["error"],
+            "type": "api_error",
+            "code": Value::Null,
+        }
+    })
+}
+
+/// Converts a response of Ollama's `/api/chat`, `/api/generate` or `/api/embed` into an
+/// OpenAI response for a request of `kind`
+pub fn openai_response(kind: RequestKind, response: &Value) -> Value {
+    if response.get("error").is_some() {
+        return openai_error(response);
+    }
+    let created = now();
+    match kind {
+        RequestKind::Chat => {
+            let message = &response["message"];
+            let tool_calls = openai_tool_calls(message, 0);
+            let mut reply = json!({ "role": "assistant", "content": message["content"] });
+            if !tool_calls.is_empty() {
+                reply["tool_calls"] = json!(tool_calls);
+            }
+            json!({
+                "id": format!("chatcmpl-{}", created),
+                "object": "chat.completion",
+                "created": created,
+                "model": response["model"],
+                "choices": [{
+                    "index": 0,
+                    "message": reply,
+                    "finish_reason": finish_reason(&response["done_reason"], !tool_calls.is_empty()),
+                }],
+                "usage": openai_usage(response),
+            })
+        }
+        RequestKind::Completion => json!({
+            "id": format!("cmpl-{}", created),
+            "object": "text_completion",
+            "created": created,
+            "model": response["model"],
+            "choices": [{
+                "index": 0,
+                "text": response["response"],
+                "logprobs": Value::Null,
+                "finish_reason": finish_reason(&response["done_reason"], false),
+            }],
+            "usage": openai_usage(response),
+        }),
+        RequestKind::Embedding => {
+            let data: Vec<Value> = response["embeddings"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .enumerate()
+                .map(|(index, embedding)| {
+                    json!({ "object": "embedding", "index": index, "embedding": embedding })
+                })
+                .collect();
+            let prompt_tokens = response["prompt_eval_count"].as_u64().unwrap_or(0);
+            json!({
+                "object": "list",
+                "data": data,
+                "model": response["model"],
+                "usage": { "prompt_tokens": prompt_tokens, "total_tokens": prompt_tokens },
+            })
+        }
+    }
+}
+
+/// Converts the response of Ollama's `/api/tags` into an OpenAI model list
+pub fn openai_models(tags: &Value) -> Map<String, Value> {
+    let models: Vec<Value> = tags["models"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|model| {
+            json!({
+                "id": model["name"],
+                "object": "model",
+                "created": 0,
+                "owned_by": "ollama",
+            })
+        })
+        .collect();
+    Map::from_iter([
+        ("object".to_string(), json!("list")),
+        ("data".to_string(), json!(models)),
+    ])
+}
+
+fn frame(value: &Value) -> String {
+    format!("data: {}\n\n", value)
+}
+
+/// Translates an Ollama response stream, one JSON object per line, into OpenAI chunks
+pub struct OpenAiStreamTranslator {
+    kind: RequestKind,
+    id: String,
+    created: u64,
+    started: bool,
+    tool_calls: usize,
+}
+
+impl OpenAiStreamTranslator {
+    pub fn new(kind: RequestKind) -> Self {
+        OpenAiStreamTranslator {
+            kind,
+            id: String::new(),
+            created: 0,
+            started: false,
+            tool_calls: 0,
+        }
+    }
+
+    fn chunk(&self, model: &Value, choices: Value) -> Value {
+        let object = match self.kind {
+            RequestKind::Chat => "chat.completion.chunk",
+            _ => "text_completion",
+        };
+        json!({
+            "id": self.id,
+            "object": object,
+            "created": self.created,
+            "model": model,
+            "choices": choices,
+        })
+    }
+
+    /// Translates one line of the Ollama stream into zero or more OpenAI SSE frames
+    pub fn line(&mut self, line: &str) -> Vec<String> {
+        let Ok(data) = serde_json::from_str::<Value>(line.trim()) else {
+            return Vec::new();
+        };
+        if data.get("error").is_some() {
+            return vec![frame(&openai_error(&data))];
+        }
+
+        let model = &data["model"];
+        let mut frames = Vec::new();
+        if !self.started {
+            self.started = true;
+            self.created = now();
+            self.id = match self.kind {
+                RequestKind::Chat => format!("chatcmpl-{}", self.created),
+                _ => format!("cmpl-{}", self.created),
+            };
+            if self.kind == RequestKind::Chat {
+                frames.push(frame(&self.chunk(
+                    model,
+                    json!([{
+                        "index": 0,
+                        "delta": { "role": "assistant", "content": "" },
+                        "finish_reason": Value::Null,
+                    }]),
+                )));
+            }
+        }
+
+        match self.kind {
+            RequestKind::Chat => {
+                let message = &data["message"];
+                if let Some(text) = message["content"].as_str().filter(|t| !t.is_empty()) {
+                    frames.push(frame(&self.chunk(
+                        model,
+                        json!([{ "index": 0, "delta": { "content": text }, "finish_reason": Value::Null }]),
+                    )));
+                }
+                let tool_calls = openai_tool_calls(message, self.tool_calls);
+                if !tool_calls.is_empty() {
+                    self.tool_calls += tool_calls.len();
+                    frames.push(frame(&self.chunk(
+                        model,
+                        json!([{ "index": 0, "delta": { "tool_calls": tool_calls }, "finish_reason": Value::Null }]),
+                    )));
+                }
+            }
+            _ => {
+                if let Some(text) = data["response"].as_str().filter(|t| !t.is_empty()) {
+                    frames.push(frame(&self.chunk(
+                        model,
+                        json!([{ "index": 0, "text": text, "finish_reason": Value::Null }]),
+                    )));
+                }
+            }
+        }
+
+        if data["done"] == true {
+            let finish_reason = finish_reason(&data["done_reason"], self.tool_calls > 0);
+            let choice = match self.kind {
+                RequestKind::Chat => {
+                    json!({ "index": 0, "delta": {}, "finish_reason": finish_reason })
+                }
+                _ => {
+                    json!({ "index": 0, "text": "", "finish_reason": finish_reason })
+                }
+            };
+            frames.push(frame(&self.chunk(model, json!([choice]))));
+            // Sent the way OpenAI does with `stream_options.include_usage`
+            let mut usage = self.chunk(model, json!([]));
+            usage["usage"] = openai_usage(&data);
+            frames.push(frame(&usage));
+            frames.push("data: [DONE]\n\n".to_string());
+        }
+        frames
+    }
+}
+
+/// Converts an Ollama `/api/chat` or `/api/generate` request into an OpenAI request of
+/// `kind`
+pub fn openai_request(kind: RequestKind, body: &Value) -> HashMap<String, Value> {
+    let mut request = HashMap::from([
+        ("model".to_string(), body["model"].clone()),
+        // Ollama clients expect a stream unless they ask otherwise
+        (
+            "stream".to_string(),
+            json!(body["stream"].as_bool().unwrap_or(true)),
+        ),
+    ]);
+
+    match kind {
+        RequestKind::Chat => {
+            let mut messages = Vec::new();
+            // Ids given to the tool calls that haven't been answered yet
+            let mut pending_calls: VecDeque<(String, Value)> = VecDeque::new();
+            let mut next_call = 0;
+            for message in body["messages"].as_array().into_iter().flatten() {
+                let images: Vec<&str> = message["images"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|i| i.as_str())
+                    .collect();
+                let content = if images.is_empty() {
+                    message["content"].clone()
+                } else {
+                    let mut parts = vec![json!({ "type": "text", "text": message["content"] })];
+                    parts.extend(images.iter().map(|data| {
+                        json!({
+                            "type": "image_url",
+                            "image_url": { "url": format!("data:{};base64,{}", media_type(data), data) },
+                        })
+                    }));
+                    json!(parts)
+                };
+                let mut openai = json!({ "role": message["role"], "content": content });
+
+                if let Some(calls) = message["tool_calls"].as_array() {
+                    let calls: Vec<Value> = calls
+                        .iter()
+                        .map(|call| {
+                            let id = format!("call_{}", next_call);
+                            next_call += 1;
+                            let function = &call["function"];
+                            pending_calls.push_back((id.clone(), function["name"].clone()));
+                            json!({
+                                "id": id,
+                                "type": "function",
+                                "function": {
+                                    "name": function["name"],
+                                    "arguments": function["arguments"].to_string(),
+                                },
+                            })
+                        })
+                        .collect();
+                    openai["tool_calls"] = json!(calls);
+                }
+                if message["role"] == "tool" {
+                    let position = pending_calls
+                        .iter()
+                        .position(|(_, name)| name == &message["tool_name"])
+                        .unwrap_or(0);
+                    if let Some((id, _)) = pending_calls.remove(position) {
+                        openai["tool_call_id"] = json!(id);
+                    }
+                }
+                messages.push(openai);
+            }
+            request.insert("messages".to_string(), json!(messages));
+            if let Some(tools) = body.get("tools") {
+                request.insert("tools".to_string(), tools.clone());
+            }
+        }
+        _ => {
+            let prompt = body["prompt"].as_str().unwrap_or_default();
+            // The completions API has no system prompt, so it goes in front of the prompt
+            let prompt = match body["system"].as_str().filter(|s| !s.is_empty()) {
+                Some(system) => format!("{}\n\n{}", system, prompt),
+                None => prompt.to_owned(),
+            };
+            request.insert("prompt".to_string(), json!(prompt));
+            if let Some(suffix) = body.get("suffix") {
+                request.insert("suffix".to_string(), suffix.clone());
+            }
+        }
+    }
+
+    for (openai, native) in OPTIONS {
+        if let Some(value) = body["options"].get(native) {
+            request.insert(openai.to_string(), value.clone());
+        }
+    }
+    match &body["format"] {
+        Value::String(format) if format == "json" => {
+            request.insert(
+                "response_format".to_string(),
+                json!({ "type": "json_object" }),
+            );
+        }
+        Value::Object(schema) => {
+            request.insert(
+                "response_format".to_string(),
+                json!({ "type": "json_schema", "json_schema": { "name": "response", "schema": schema } }),
+            );
+        }
+        _ => {}
+    }
+    request
+}
+
+fn done_reason(finish_reason: &Value) -> &'static str {
+    match finish_reason.as_str() {
+        Some("length") => "length",
+        _ => "stop",
+    }
+}
+
+/// Converts an OpenAI error body into the Ollama error format
+pub fn native_error(body: &Value) -> Value {
+    json!({ "error": body["error"]["message"] })
+}
+
+/// Ollama tool calls for the tool calls of an OpenAI message
+fn native_tool_calls(calls: &[(Value, String)]) -> Vec<Value> {
+    calls
+        .iter()
+        .map(|(name, arguments)| {
+            let arguments = serde_json::from_str::<Value>(arguments).unwrap_or_else(|_| json!({}));
+            json!({ "function": { "name": name, "arguments": arguments } })
+        })
+        .collect()
+}
+
+/// Converts an OpenAI response into the response of Ollama's `/api/chat` or
+/// `/api/generate`, for a request of `kind`
+pub fn native_response(kind: RequestKind, response: &Value) -> Value {
+    if response.get("error").is_some() {
+        return native_error(response);
+    }
+    let choice = &response["choices"][0];
+    let mut reply = json!({
+        "model": response["model"],
+        "created_at": timestamp(response["created"].as_u64().unwrap_or_else(now)),
+        "done": true,
+        "done_reason": done_reason(&choice["finish_reason"]),
+        "prompt_eval_count": response["usage"]["prompt_tokens"],
+        "eval_count": response["usage"]["completion_tokens"],
+    });
+    match kind {
+        RequestKind::Chat => {
+            let message = &choice["message"];
+            let calls: Vec<(Value, String)> = message["tool_calls"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|call| {
+                    let function = &call["function"];
+                    let arguments = function["arguments"].as_str().unwrap_or("{}");
+                    (function["name"].clone(), arguments.to_owned())
+                })
+                .collect();
+            let mut message = json!({
+                "role": "assistant",
+                "content": message["content"].as_str().unwrap_or_default(),
+            });
+            if !calls.is_empty() {
+                message["tool_calls"] = json!(native_tool_calls(&calls));
+            }
+            reply["message"] = message;
+        }
+        _ => {
+            reply["response"] = json!(choice["text"].as_str().unwrap_or_default());
+        }
+    }
+    reply
+}
+
+/// Lists models in the format of Ollama's `/api/tags`
+pub fn native_tags(models: &[Value]) -> Value {
+    let models: Vec<Value> = models
+        .iter()
+        .filter_map(|model| {
+            let id = model["id"].as_str()?;
+            Some(json!({
+                "name": id,
+                "model": id,
+                "modified_at": timestamp(model["created"].as_u64().unwrap_or_else(now)),
+                "size": 0,
+                "digest": "",
+                "details": {},
+            }))
+        })
+        .collect();
+    json!({ "models": models })
+}
+
+/// Translates an OpenAI chunk stream into an Ollama response stream, one JSON object per
+/// line
+pub struct NativeStreamTranslator {
+    kind: RequestKind,
+    model: Value,
+    started: bool,
+    finished: bool,
+    /// Name and arguments so far of each tool call, Ollama sends them whole
+    tool_calls: Vec<(Value, String)>,
+    finish_reason: Value,
+    usage: Value,
+}
+
+impl NativeStreamTranslator {
+    /// `model` is reported until the upstream names the model it used
+    pub fn new(kind: RequestKind, model: Value) -> Self {
+        NativeStreamTranslator {
+            kind,
+            model,
+            started: false,
+            finished: false,
+            tool_calls: Vec::new(),
+            finish_reason: Value::Null,
+            usage: Value::Null,
+        }
+    }
+
+    fn line(&self, content: &str, done: bool) -> Value {
+        let mut line = json!({
+            "model": self.model,
+            "created_at": timestamp(now()),
+            "done": done,
+        });
+        match self.kind {
+            RequestKind::Chat => {
+                line["message"] = json!({ "role": "assistant", "content": content })
+            }
+            _ => line["response"] = json!(content),
+        }
+        line
+    }
+
+    /// Translates one OpenAI SSE event into zero or more Ollama lines
+    pub fn event(&mut self, event: &sse::Event) -> Vec<String> {
+        if event.data.trim() == "[DONE]" {
+            return self.finish();
+        }
+        let Ok(chunk) = serde_json::from_str::<Value>(&event.data) else {
+            return Vec::new();
+        };
+        if chunk.get("error").is_some() {
+            return vec![format!("{}\n", native_error(&chunk))];
+        }
+
+        self.started = true;
+        if !chunk["model"].is_null() {
+            self.model = chunk["model"].clone();
+        }
+        if chunk["usage"].is_object() {
+            self.usage = chunk["usage"].clone();
+        }
+
+        let mut lines = Vec::new();
+        for choice in chunk["choices"].as_array().into_iter().flatten() {
+            let text = match self.kind {
+                RequestKind::Chat => choice["delta"]["content"].as_str(),
+                _ => choice["text"].as_str(),
+            };
+            if let Some(text) = text.filter(|t| !t.is_empty()) {
+                lines.push(format!("{}\n", self.line(text, false)));
+            }
+            for call in choice["delta"]["tool_calls"]
+                .as_array()
+                .into_iter()
+                .flatten()
+            {
+                let index = call["index"].as_u64().unwrap_or_default() as usize;
+                if index >= MAX_TOOL_CALLS {
+                    continue;
+                }
+                if index >= self.tool_calls.len() {
+                    self.tool_calls
+                        .resize(index + 1, (Value::Null, String::new()));
+                }
+                let function = &call["function"];
+                if !function["name"].is_null() {
+                    self.tool_calls[index].0 = function["name"].clone();
+                }
+                if let Some(arguments) = function["arguments"].as_str() {
+                    self.tool_calls[index].1.push_str(arguments);
+                }
+            }
+            if !choice["finish_reason"].is_null() {
+                self.finish_reason = choice["finish_reason"].clone();
+            }
+        }
+        lines
+    }
+
+    /// Sends the final line with the token counts, once the OpenAI stream is over
+    pub fn finish(&mut self) -> Vec<String> {
+        if !self.started || self.finished {
+            return Vec::new();
+        }
+        self.finished = true;
+        let mut line = self.line("", true);
+        if !self.tool_calls.is_empty() {
+            line["message"]["tool_calls"] = json!(native_tool_calls(&self.tool_calls));
+        }
+        line["done_reason"] = json!(done_reason(&self.finish_reason));
+        line["prompt_eval_count"] = self.usage["prompt_tokens"].clone();
+        line["eval_count"] = self.usage["completion_tokens"].clone();
+        vec![format!("{}\n", line)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{payloads, sse};
+
+    fn body(value: Value) -> HashMap<String, Value> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(timestamp(951_827_696), "2000-02-29T12:34:56Z");
+    }
+
+    #[test]
+    fn native_request_translates_messages_tools_and_options() {
+        let request = native_request(
+            RequestKind::Chat,
+            &body(json!({
+                "model": "llama",
+                "messages": [
+                    { "role": "user", "content": [
+                        { "type": "text", "text": "What is this?" },
+                        { "type": "image_url", "image_url": { "url": "data:image/png;base64,iVBO" } },
+                        { "type": "image_url", "image_url": { "url": "https://example.com/a.png" } },
+                    ] },
+                    { "role": "assistant", "content": null, "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": { "name": "lookup", "arguments": "{\"q\":\"cat\"}" },
+                    }] },
+                    { "role": "tool", "tool_call_id": "call_1", "content": "A cat" },
+                ],
+                "temperature": 0.2,
+                "stop": "END",
+                "max_completion_tokens": 50,
+                "response_format": { "type": "json_object" },
+            })),
+        )
+        .unwrap();
+        assert_eq!(request["stream"], false);
+        assert_eq!(
+            request["messages"],
+            json!([
+                { "role": "user", "content": "What is this?", "images": ["iVBO"] },
+                { "role": "assistant", "content": "", "tool_calls": [
+                    { "function": { "name": "lookup", "arguments": { "q": "cat" } } },
+                ] },
+                { "role": "tool", "content": "A cat", "tool_name": "lookup" },
+            ])
+        );
+        assert_eq!(
+            request["options"],
+            json!({ "temperature": 0.2, "stop": ["END"], "num_predict": 50 })
+        );
+        assert_eq!(request["format"], "json");
+    }
+
+    #[test]
+    fn native_request_sends_every_prompt_of_a_batch() {
+        let request = native_request(
+            RequestKind::Completion,
+            &body(json!({ "prompt": ["Once upon", "a time"] })),
+        )
+        .unwrap();
+        assert_eq!(request["prompt"], "Once upon\na time");
+    }
+
+    #[test]
+    fn native_request_rejects_token_prompts() {
+        for prompt in [
+            json!([1, 2, 3]),
+            json!([[1, 2, 3]]),
+            json!(["Once upon", [4]]),
+        ] {
+            let request =
+                native_request(RequestKind::Completion, &body(json!({ "prompt": prompt })));
+            assert_eq!(request.unwrap_err(), "Ollama doesn't take token prompts");
+        }
+    }
+
+    #[test]
+    fn openai_response_translates_chat_completion_and_embedding() {
+        let chat = openai_response(
+            RequestKind::Chat,
+            &json!({
+                "model": "llama",
+                "message": { "role": "assistant", "content": "", "tool_calls": [
+                    { "function": { "name": "lookup", "arguments": { "q": "cat" } } },
+                ] },
+                "done_reason": "stop",
+                "prompt_eval_count": 12,
+                "eval_count": 3,
+            }),
+        );
+        let choice = &chat["choices"][0];
+        assert_eq!(
+            choice["message"]["tool_calls"][0]["function"],
+            json!({ "name": "lookup", "arguments": "{\"q\":\"cat\"}" })
+        );
+        assert_eq!(choice["finish_reason"], "tool_calls");
+        assert_eq!(
+            chat["usage"],
+            json!({ "prompt_tokens": 12, "completion_tokens": 3, "total_tokens": 15 })
+        );
+
+        let completion = openai_response(
+            RequestKind::Completion,
+            &json!({ "response": "Hi", "done_reason": "length" }),
+        );
+        assert_eq!(completion["choices"][0]["text"], "Hi");
+        assert_eq!(completion["choices"][0]["finish_reason"], "length");
+
+        let embedding = openai_response(
+            RequestKind::Embedding,
+            &json!({ "embeddings": [[0.1], [0.2]], "prompt_eval_count": 4 }),
+        );
+        assert_eq!(embedding["data"][1]["index"], 1);
+        assert_eq!(embedding["usage"]["total_tokens"], 4);
+
+        let error = openai_response(RequestKind::Chat, &json!({ "error": "model not found" }));
+        assert_eq!(error["error"]["message"], "model not found");
+    }
+
+    #[test]
+    fn openai_stream_translator_translates_text_tool_calls_and_usage() {
+        let mut translator = OpenAiStreamTranslator::new(RequestKind::Chat);
+        let lines = [
+            json!({ "model": "llama", "message": { "content": "Hi" }, "done": false }),
+            json!({ "model": "llama", "message": { "content": "", "tool_calls": [
+                { "function": { "name": "lookup", "arguments": { "q": "cat" } } },
+            ] }, "done": false }),
+            json!({ "model": "llama", "message": { "content": "" }, "done": true,
+                "done_reason": "stop", "prompt_eval_count": 12, "eval_count": 3 }),
+        ];
+        let frames: Vec<String> = lines
+            .iter()
+            .flat_map(|line| translator.line(&line.to_string()))
+            .collect();
+        assert_eq!(frames.last().unwrap(), "data: [DONE]\n\n");
+        let chunks = payloads(&frames);
+        assert_eq!(chunks[0]["choices"][0]["delta"]["role"], "assistant");
+        assert_eq!(chunks[1]["choices"][0]["delta"]["content"], "Hi");
+        assert_eq!(
+            chunks[2]["choices"][0]["delta"]["tool_calls"][0]["id"],
+            "call_0"
+        );
+        assert_eq!(chunks[3]["choices"][0]["finish_reason"], "tool_calls");
+        assert_eq!(chunks[4]["usage"]["total_tokens"], 15);
+    }
+
+    #[test]
+    fn openai_request_pairs_tool_results_with_calls() {
+        let request = openai_request(
+            RequestKind::Chat,
+            &json!({
+                "model": "llama",
+                "messages": [
+                    { "role": "user", "content": "Look up cat and dog", "images": ["R0lG"] },
+                    { "role": "assistant", "content": "", "tool_calls": [
+                        { "function": { "name": "cat", "arguments": {} } },
+                        { "function": { "name": "dog", "arguments": {} } },
+                    ] },
+                    { "role": "tool", "tool_name": "dog", "content": "Woof" },
+                    { "role": "tool", "tool_name": "cat", "content": "Meow" },
+                ],
+                "options": { "num_predict": 10 },
+                "format": "json",
+            }),
+        );
+        let messages = &request["messages"];
+        assert_eq!(
+            messages[0]["content"][1]["image_url"]["url"],
+            "data:image/gif;base64,R0lG"
+        );
+        assert_eq!(messages[1]["tool_calls"][1]["id"], "call_1");
+        assert_eq!(messages[2]["tool_call_id"], "call_1");
+        assert_eq!(messages[3]["tool_call_id"], "call_0");
+        assert_eq!(request["stream"], true);
+        assert_eq!(request["max_tokens"], 10);
+        assert_eq!(request["response_format"], json!({ "type": "json_object" }));
+
+        let completion = openai_request(
+            RequestKind::Completion,
+            &json!({ "prompt": "Hello", "system": "Be brief", "stream": false }),
+        );
+        assert_eq!(completion["prompt"], "Be brief\n\nHello");
+    }
+
+    #[test]
+    fn native_response_translates_tool_calls_and_usage() {
+        let response = native_response(
+            RequestKind::Chat,
+            &json!({
+                "model": "gpt",
+                "created": 0,
+                "choices": [{
+                    "message": { "content": null, "tool_calls": [{
+                        "function": { "name": "lookup", "arguments": "{\"q\":\"cat\"}" },
+                    }] },
+                    "finish_reason": "length",
+                }],
+                "usage": { "prompt_tokens": 12, "completion_tokens": 3 },
+            }),
+        );
+        assert_eq!(
+            response,
+            json!({
+                "model": "gpt",
+                "created_at": "1970-01-01T00:00:00Z",
+                "done": true,
+                "done_reason": "length",
+                "prompt_eval_count": 12,
+                "eval_count": 3,
+                "message": { "role": "assistant", "content": "", "tool_calls": [
+                    { "function": { "name": "lookup", "arguments": { "q": "cat" } } },
+                ] },
+            })
+        );
+        let error = native_response(
+            RequestKind::Chat,
+            &json!({ "error": { "message": "Rate limited" } }),
+        );
+        assert_eq!(error, json!({ "error": "Rate limited" }));
+    }
+
+    #[test]
+    fn native_stream_translator_collects_tool_calls_and_usage() {
+        let mut translator = NativeStreamTranslator::new(RequestKind::Chat, json!("alias"));
+        let chunks = [
+            json!({ "model": "gpt", "choices": [{ "delta": { "content": "Hi" } }] }),
+            json!({ "choices": [{ "delta": { "tool_calls": [
+                { "index": 0, "function": { "name": "lookup", "arguments": "{\"q\":" } },
+            ] } }] }),
+            json!({ "choices": [{ "delta": { "tool_calls": [
+                { "index": 0, "function": { "arguments": "\"cat\"}" } },
+            ] }, "finish_reason": "tool_calls" }] }),
+            json!({ "choices": [], "usage": { "prompt_tokens": 12, "completion_tokens": 3 } }),
+        ];
+        let mut lines: Vec<String> = chunks
+            .into_iter()
+            .flat_map(|chunk| translator.event(&sse(None, chunk)))
+            .collect();
+        lines.extend(translator.event(&sse::Event {
+            event: None,
+            data: "[DONE]".to_string(),
+        }));
+        assert!(translator.finish().is_empty());
+
+        let lines: Vec<Value> = lines
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["model"], "gpt");
+        assert_eq!(lines[0]["message"]["content"], "Hi");
+        assert_eq!(lines[1]["done"], true);
+        assert_eq!(
+            lines[1]["message"]["tool_calls"],
+            json!([{ "function": { "name": "lookup", "arguments": { "q": "cat" } } }])
+        );
+        assert_eq!(lines[1]["prompt_eval_count"], 12);
+        assert_eq!(lines[1]["eval_count"], 3);
+    }
+
+    #[test]
+    fn native_stream_translator_drops_tool_calls_with_huge_indices() {
+        let mut translator = NativeStreamTranslator::new(RequestKind::Chat, json!("gpt"));
+        translator.event(&sse(
+            None,
+            json!({ "choices": [{ "delta": { "tool_calls": [
+            { "index": u64::MAX, "function": { "name": "lookup", "arguments": "{}" } },
+        ] } }] }),
+        ));
+        assert!(translator.tool_calls.is_empty());
+    }
+}
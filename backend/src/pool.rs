@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+
+use indexmap::IndexMap;
+use rand::Rng;
+
+use crate::config::{AppConfig, PoolConfig, PoolStrategy, Preset, ProviderConfig};
+
+/// Next round-robin position of each pool
+static ROUND_ROBIN: LazyLock<Mutex<HashMap<String, usize>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Requests currently being served by each provider
+static IN_FLIGHT: LazyLock<Mutex<HashMap<String, usize>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Counts a request as in flight for a provider until dropped
+pub struct InFlight {
+    provider_id: String,
+}
+
+impl InFlight {
+    pub fn start(provider_id: &str) -> Self {
+        *IN_FLIGHT
+            .lock()
+            .unwrap()
+            .entry(provider_id.to_owned())
+            .or_default() += 1;
+        InFlight {
+            provider_id: provider_id.to_owned(),
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(count) = IN_FLIGHT.lock().unwrap().get_mut(&self.provider_id) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+fn in_flight(provider_id: &str) -> usize {
+    IN_FLIGHT
+        .lock()
+        .unwrap()
+        .get(provider_id)
+        .copied()
+        .unwrap_or(0)
+}
+
+/// Orders the active members of `pool`, the member that should serve the request first
+fn order_members(pool_id: &str, pool: &PoolConfig) -> Vec<String> {
+    let mut members: Vec<_> = pool.members.iter().filter(|m| m.weight > 0).collect();
+    if members.is_empty() {
+        return Vec::new();
+    }
+    match pool.strategy {
+        PoolStrategy::RoundRobin => {
+            let mut positions = ROUND_ROBIN.lock().unwrap();
+            let position = positions.entry(pool_id.to_owned()).or_default();
+            let start = *position % members.len();
+            *position = position.wrapping_add(1);
+            members.rotate_left(start);
+        }
+        PoolStrategy::WeightedRandom => {
+            // Weighted shuffle, so the members after the first are still in a sensible order
+            let mut rng = rand::thread_rng();
+            let mut shuffled = Vec::with_capacity(members.len());
+            while !members.is_empty() {
+                let total: u32 = members.iter().map(|m| m.weight).sum();
+                let mut pick = rng.gen_range(0..total);
+                let index = members
+                    .iter()
+                    .position(|m| {
+                        if pick < m.weight {
+                            true
+                        } else {
+                            pick -= m.weight;
+                            false
+                        }
+                    })
+                    .unwrap();
+                shuffled.push(members.remove(index));
+            }
+            members = shuffled;
+        }
+        PoolStrategy::LeastInFlight => {
+            members.sort_by_key(|m| in_flight(&m.provider));
+        }
+    }
+    members.into_iter().map(|m| m.provider.clone()).collect()
+}
+
+/// The member provider as it should be used on behalf of `pool`. The pool's active preset
+/// is layered over the member's own one.
+fn as_member(pool: &ProviderConfig, member: &ProviderConfig) -> ProviderConfig {
+    let mut member = member.clone();
+    let pool_preset = pool
+        .preset
+        .as_ref()
+        .and_then(|id| pool.presets.iter().find(|p| &p.id == id));
+    if let Some(pool_preset) = pool_preset {
+        let mut overrides: IndexMap<String, serde_json::Value> = member
+            .preset
+            .as_ref()
+            .and_then(|id| member.presets.iter().find(|p| &p.id == id))
+            .map(|p| p.overrides.clone())
+            .unwrap_or_default();
+        overrides.extend(pool_preset.overrides.clone());
+        member.presets = vec![Preset {
+            id: pool_preset.id.clone(),
+            name: pool_preset.name.clone(),
+            overrides,
+        }];
+        member.preset = Some(pool_preset.id.clone());
+    }
+    member
+}
+
+/// Replaces every pool in `chain` by its members, in the order the pool's strategy picks
+/// them
+pub fn expand(config: &AppConfig, chain: Vec<ProviderConfig>) -> Vec<ProviderConfig> {
+    let mut expanded: Vec<ProviderConfig> = Vec::new();
+    for provider in chain {
+        let Some(pool) = &provider.pool else {
+            if !expanded.iter().any(|p| p.id == provider.id) {
+                expanded.push(provider);
+            }
+            continue;
+        };
+        for id in order_members(&provider.id, pool) {
+            if expanded.iter().any(|p| p.id == id) {
+                continue;
+            }
+            if let Some(member) = config.providers.iter().find(|p| p.id == id) {
+                expanded.push(as_member(&provider, member));
+            }
+        }
+    }
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::provider;
+    use serde_json::json;
+
+    /// A config with a pool `pool_id` over the weighted `members`, and the members.
+    /// Every test uses its own ids, the round-robin positions and in-flight counts are
+    /// shared.
+    fn config(pool_id: &str, strategy: &str, members: &[(&str, u32)]) -> AppConfig {
+        let mut providers: Vec<ProviderConfig> =
+            members.iter().map(|(id, _)| provider(id)).collect();
+        providers.push(
+            serde_json::from_value(json!({
+                "name": pool_id,
+                "id": pool_id,
+                "presets": [],
+                "pool": {
+                    "strategy": strategy,
+                    "members": members
+                        .iter()
+                        .map(|(id, weight)| json!({ "provider": id, "weight": weight }))
+                        .collect::<Vec<_>>(),
+                },
+            }))
+            .unwrap(),
+        );
+        AppConfig {
+            providers,
+            ..Default::default()
+        }
+    }
+
+    fn expand_ids(config: &AppConfig, chain: &[&str]) -> Vec<String> {
+        let chain = chain
+            .iter()
+            .map(|id| {
+                config
+                    .providers
+                    .iter()
+                    .find(|p| p.id == *id)
+                    .unwrap()
+                    .clone()
+            })
+            .collect();
+        expand(config, chain).into_iter().map(|p| p.id).collect()
+    }
+
+    #[test]
+    fn round_robin_rotates_the_members() {
+        let config = config("rr", "round_robin", &[("rr1", 1), ("rr2", 1), ("rr3", 1)]);
+        assert_eq!(expand_ids(&config, &["rr"]), ["rr1", "rr2", "rr3"]);
+        assert_eq!(expand_ids(&config, &["rr"]), ["rr2", "rr3", "rr1"]);
+        assert_eq!(expand_ids(&config, &["rr"]), ["rr3", "rr1", "rr2"]);
+        assert_eq!(expand_ids(&config, &["rr"]), ["rr1", "rr2", "rr3"]);
+    }
+
+    #[test]
+    fn members_without_weight_are_left_out() {
+        let config = config("w0", "weighted_random", &[("w0a", 0), ("w0b", 3)]);
+        for _ in 0..20 {
+            assert_eq!(expand_ids(&config, &["w0"]), ["w0b"]);
+        }
+    }
+
+    #[test]
+    fn weighted_random_prefers_heavier_members() {
+        let config = config("wr", "weighted_random", &[("wr1", 1), ("wr2", 99)]);
+        let first_heavy = (0..200)
+            .filter(|_| expand_ids(&config, &["wr"])[0] == "wr2")
+            .count();
+        assert!(
+            first_heavy > 150,
+            "heavier member first {first_heavy} times"
+        );
+        let mut all = expand_ids(&config, &["wr"]);
+        all.sort();
+        assert_eq!(all, ["wr1", "wr2"]);
+    }
+
+    #[test]
+    fn least_in_flight_orders_by_open_requests() {
+        let config = config(
+            "lf",
+            "least_in_flight",
+            &[("lf1", 1), ("lf2", 1), ("lf3", 1)],
+        );
+        let busy = [
+            InFlight::start("lf1"),
+            InFlight::start("lf1"),
+            InFlight::start("lf2"),
+        ];
+        assert_eq!(expand_ids(&config, &["lf"]), ["lf3", "lf2", "lf1"]);
+        drop(busy);
+        assert_eq!(expand_ids(&config, &["lf"]), ["lf1", "lf2", "lf3"]);
+    }
+
+    #[test]
+    fn providers_already_in_the_chain_are_not_repeated() {
+        let config = config("dd", "round_robin", &[("dd1", 1), ("dd2", 1)]);
+        let expanded = expand_ids(&config, &["dd2", "dd"]);
+        assert_eq!(expanded, ["dd2", "dd1"]);
+    }
+
+    #[test]
+    fn pool_preset_is_layered_over_the_member_preset() {
+        let mut config = config("pp", "round_robin", &[("pp1", 1)]);
+        config.providers[0].presets = vec![Preset {
+            id: "own".to_string(),
+            name: "Own".to_string(),
+            overrides: IndexMap::from([
+                ("temperature".to_string(), json!(0.2)),
+                ("top_p".to_string(), json!(0.9)),
+            ]),
+        }];
+        config.providers[0].preset = Some("own".to_string());
+        config.providers[1].presets = vec![Preset {
+            id: "pool".to_string(),
+            name: "Pool".to_string(),
+            overrides: IndexMap::from([("temperature".to_string(), json!(0.7))]),
+        }];
+        config.providers[1].preset = Some("pool".to_string());
+
+        let member = expand(&config, vec![config.providers[1].clone()]).remove(0);
+        assert_eq!(member.id, "pp1");
+        assert_eq!(member.preset.as_deref(), Some("pool"));
+        assert_eq!(
+            member.presets[0].overrides,
+            IndexMap::from([
+                ("temperature".to_string(), json!(0.7)),
+                ("top_p".to_string(), json!(0.9)),
+            ])
+        );
+    }
+}
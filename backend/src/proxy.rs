@@ -0,0 +1,793 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::warn;
+use reqwest::header::HeaderMap;
+use reqwest::{Client, RequestBuilder, StatusCode};
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+
+use crate::anthropic;
+use crate::config::{AppConfig, ProviderConfig, ProviderKind};
+use crate::db;
+use crate::error::{self, ApiError};
+use crate::ollama;
+use crate::pool::{self, InFlight};
+use crate::sse;
+
+/// The OpenAI-style endpoints the proxy forwards
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    Chat,
+    Completion,
+    Embedding,
+}
+
+impl RequestKind {
+    pub fn path(self) -> &'static str {
+        match self {
+            RequestKind::Chat => "chat/completions",
+            RequestKind::Completion => "completions",
+            RequestKind::Embedding => "embeddings",
+        }
+    }
+
+    /// Model logged when the request doesn't name one
+    pub fn default_model(self) -> &'static str {
+        match self {
+            RequestKind::Chat => "gpt-3.5-turbo",
+            RequestKind::Completion => "gpt-3.5-turbo-instruct",
+            RequestKind::Embedding => "text-embedding-ada-002",
+        }
+    }
+
+    /// Value of the `request_type` log column
+    pub fn name(self) -> &'static str {
+        match self {
+            RequestKind::Chat => "chat",
+            RequestKind::Completion => "completion",
+            RequestKind::Embedding => "embedding",
+        }
+    }
+}
+
+/// Per-request overrides of the active provider and preset, sent by clients that can't
+/// change the model string
+pub struct ProxyOverrides {
+    pub provider: Option<String>,
+    pub preset: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ProxyOverrides {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let header = |name| {
+            request
+                .headers()
+                .get_one(name)
+                .map(|v| v.trim().to_owned())
+                .filter(|v| !v.is_empty())
+        };
+        request::Outcome::Success(ProxyOverrides {
+            provider: header("X-AISwitch-Provider"),
+            preset: header("X-AISwitch-Preset"),
+        })
+    }
+}
+
+/// Picks the provider for a request. Aliased model names are resolved first. A model such
+/// as `openrouter/meta-llama-3` selects the provider with id `openrouter` and is forwarded
+/// as `meta-llama-3`, any other model goes to the provider named in the
+/// `X-AISwitch-Provider` header or, failing that, the active one.
+/// The returned provider has the effective preset selected.
+pub fn select_provider(
+    config: &AppConfig,
+    overrides: &ProxyOverrides,
+    body: &mut HashMap<String, serde_json::Value>,
+) -> Result<ProviderConfig, ApiError> {
+    let alias = body
+        .get("model")
+        .and_then(|m| m.as_str())
+        .and_then(|m| config.aliases.get(m));
+    if let Some(alias) = alias {
+        let mut provider = match config.providers.iter().find(|p| p.id == alias.provider) {
+            Some(provider) => provider.clone(),
+            None => {
+                return Err(ApiError::unavailable(format!(
+                    "Alias points to unknown provider '{}'",
+                    alias.provider
+                )))
+            }
+        };
+        body.insert(
+            "model".to_string(),
+            serde_json::Value::String(alias.model.clone()),
+        );
+        if alias.preset.is_some() {
+            provider.preset = alias.preset.clone();
+            return Ok(provider);
+        }
+        return with_preset_override(provider, overrides);
+    }
+
+    let prefixed = body
+        .get("model")
+        .and_then(|m| m.as_str())
+        .and_then(|m| m.split_once('/'))
+        .and_then(|(provider_id, model)| {
+            config
+                .providers
+                .iter()
+                .find(|p| p.id == provider_id)
+                .map(|p| (p, model.to_owned()))
+        });
+
+    let provider = if let Some((provider, model)) = prefixed {
+        body.insert("model".to_string(), serde_json::Value::String(model));
+        provider.clone()
+    } else if let Some(provider_id) = &overrides.provider {
+        match config.providers.iter().find(|p| &p.id == provider_id) {
+            Some(provider) => provider.clone(),
+            None => {
+                return Err(ApiError::invalid_request(format!(
+                    "Unknown provider '{}'",
+                    provider_id
+                ))
+                .with_code("provider_not_found"))
+            }
+        }
+    } else {
+        let provider = config
+            .provider
+            .as_ref()
+            .and_then(|id| config.providers.iter().find(|p| &p.id == id));
+        match provider {
+            Some(provider) => provider.clone(),
+            None => return Err(ApiError::unavailable("No active provider")),
+        }
+    };
+
+    with_preset_override(provider, overrides)
+}
+
+/// Selects the preset requested through the `X-AISwitch-Preset` header, if any
+fn with_preset_override(
+    mut provider: ProviderConfig,
+    overrides: &ProxyOverrides,
+) -> Result<ProviderConfig, ApiError> {
+    if let Some(preset_id) = &overrides.preset {
+        if !provider.presets.iter().any(|p| &p.id == preset_id) {
+            return Err(ApiError::invalid_request(format!(
+                "Unknown preset '{}' for provider '{}'",
+                preset_id, provider.id
+            ))
+            .with_code("preset_not_found"));
+        }
+        provider.preset = Some(preset_id.clone());
+    }
+    Ok(provider)
+}
+
+pub fn apply_preset(provider: &ProviderConfig, body: &mut HashMap<String, serde_json::Value>) {
+    if let Some(preset) = &provider.preset {
+        if let Some(preset) = provider.presets.iter().find(|p| &p.id == preset) {
+            for prop in preset.overrides.iter() {
+                body.insert(prop.0.to_owned(), prop.1.to_owned());
+            }
+        }
+    }
+}
+
+/// Providers to try for a request that selected `first`, with pools resolved to members
+pub fn upstream_chain(config: &AppConfig, first: ProviderConfig) -> Vec<ProviderConfig> {
+    pool::expand(config, config.failover_chain(first))
+}
+
+/// Adds the provider's credentials to an upstream request
+pub fn authorize(provider: &ProviderConfig, request: RequestBuilder) -> RequestBuilder {
+    match provider.kind {
+        ProviderKind::OpenAi => {
+            request.header("Authorization", format!("Bearer {}", provider.api_key))
+        }
+        ProviderKind::Anthropic => request
+            .header("x-api-key", &provider.api_key)
+            .header("anthropic-version", anthropic::API_VERSION),
+        // Ollama has no authentication of its own, a key is only needed behind a proxy
+        ProviderKind::Ollama if provider.api_key.is_empty() => request,
+        ProviderKind::Ollama => {
+            request.header("Authorization", format!("Bearer {}", provider.api_key))
+        }
+    }
+}
+
+/// The URL and body to send to `provider` for a request of `kind`, translated to the
+/// provider's API. Fails if the provider can't serve the request.
+fn upstream_request(
+    provider: &ProviderConfig,
+    kind: RequestKind,
+    body: &HashMap<String, serde_json::Value>,
+) -> Result<(String, serde_json::Value), ApiError> {
+    match (provider.kind, kind) {
+        (ProviderKind::OpenAi, _) => Ok((
+            format!("{}/{}", provider.api_url, kind.path()),
+            serde_json::to_value(body).unwrap(),
+        )),
+        (ProviderKind::Anthropic, RequestKind::Chat) => Ok((
+            format!("{}/messages", provider.api_url),
+            anthropic::messages_request(body),
+        )),
+        (ProviderKind::Anthropic, RequestKind::Completion | RequestKind::Embedding) => {
+            Err(ApiError::invalid_request(format!(
+                "Provider '{}' does not support {}",
+                provider.id,
+                kind.path()
+            )))
+        }
+        (ProviderKind::Ollama, _) => {
+            let path = match kind {
+                RequestKind::Chat => "api/chat",
+                RequestKind::Completion => "api/generate",
+                RequestKind::Embedding => "api/embed",
+            };
+            let body = ollama::native_request(kind, body).map_err(|e| {
+                ApiError::invalid_request(format!(
+                    "Provider '{}' can't serve this request: {}",
+                    provider.id, e
+                ))
+            })?;
+            Ok((format!("{}/{}", provider.api_url, path), body))
+        }
+    }
+}
+
+enum ResponseFormat {
+    OpenAi {
+        /// Trailing bytes of a UTF-8 character split across chunks
+        partial: Vec<u8>,
+    },
+    Anthropic {
+        decoder: sse::Decoder,
+        translator: Box<anthropic::ChatStreamTranslator>,
+    },
+    Ollama {
+        kind: RequestKind,
+        /// Bytes of the line being received
+        line: Vec<u8>,
+        translator: Box<ollama::OpenAiStreamTranslator>,
+    },
+}
+
+/// An upstream response, read back in the OpenAI format whatever API the provider speaks
+pub struct UpstreamResponse {
+    response: reqwest::Response,
+    format: ResponseFormat,
+    /// Translated frames not handed out yet
+    pending: VecDeque<String>,
+}
+
+impl UpstreamResponse {
+    fn new(provider: &ProviderConfig, kind: RequestKind, response: reqwest::Response) -> Self {
+        let format = match provider.kind {
+            ProviderKind::OpenAi => ResponseFormat::OpenAi {
+                partial: Vec::new(),
+            },
+            ProviderKind::Anthropic => ResponseFormat::Anthropic {
+                decoder: sse::Decoder::default(),
+                translator: Box::default(),
+            },
+            ProviderKind::Ollama => ResponseFormat::Ollama {
+                kind,
+                line: Vec::new(),
+                translator: Box::new(ollama::OpenAiStreamTranslator::new(kind)),
+            },
+        };
+        UpstreamResponse {
+            response,
+            format,
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        Status::new(self.response.status().as_u16())
+    }
+
+    /// Headers of the upstream response that are passed on to the client: request ids and
+    /// rate limit information. The content type is set by the routes, as translation may
+    /// change it.
+    pub fn forwarded_headers(&self) -> Vec<(String, String)> {
+        self.response
+            .headers()
+            .iter()
+            .filter(|(name, _)| {
+                let name = name.as_str();
+                name == "retry-after"
+                    || name == "x-request-id"
+                    || name == "request-id"
+                    || name.starts_with("x-ratelimit-")
+                    || name.starts_with("anthropic-ratelimit-")
+            })
+            .filter_map(|(name, value)| {
+                Some((name.as_str().to_owned(), value.to_str().ok()?.to_owned()))
+            })
+            .collect()
+    }
+
+    pub async fn text(self) -> Result<String, reqwest::Error> {
+        let text = self.response.text().await?;
+        match self.format {
+            ResponseFormat::OpenAi { .. } => Ok(text),
+            ResponseFormat::Anthropic { .. } => {
+                Ok(match serde_json::from_str::<serde_json::Value>(&text) {
+                    Ok(body) if body["type"] == "error" => anthropic::chat_error(&body).to_string(),
+                    Ok(body) => anthropic::chat_response(&body).to_string(),
+                    Err(_) => text,
+                })
+            }
+            ResponseFormat::Ollama { kind, .. } => {
+                Ok(match serde_json::from_str::<serde_json::Value>(&text) {
+                    Ok(body) => ollama::openai_response(kind, &body).to_string(),
+                    Err(_) => text,
+                })
+            }
+        }
+    }
+
+    /// Reads the next part of a streamed response as OpenAI SSE text. Parts don't line up
+    /// with events, they are meant to be fed to an `sse::Decoder`.
+    pub async fn chunk(&mut self) -> Result<Option<String>, reqwest::Error> {
+        loop {
+            if let Some(frame) = self.pending.pop_front() {
+                return Ok(Some(frame));
+            }
+            let bytes = self.response.chunk().await?;
+            match &mut self.format {
+                ResponseFormat::OpenAi { partial } => {
+                    let Some(bytes) = bytes else {
+                        let rest = std::mem::take(partial);
+                        return Ok(
+                            (!rest.is_empty()).then(|| String::from_utf8_lossy(&rest).into_owned())
+                        );
+                    };
+                    partial.extend_from_slice(&bytes);
+                    let complete = match std::str::from_utf8(partial) {
+                        Ok(_) => partial.len(),
+                        // Keep an incomplete character for the next chunk
+                        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+                        Err(_) => partial.len(),
+                    };
+                    let text: Vec<u8> = partial.drain(..complete).collect();
+                    if !text.is_empty() {
+                        return Ok(Some(String::from_utf8_lossy(&text).into_owned()));
+                    }
+                }
+                ResponseFormat::Anthropic {
+                    decoder,
+                    translator,
+                } => match bytes {
+                    Some(bytes) => {
+                        for event in decoder.feed(&bytes) {
+                            self.pending.extend(translator.event(&event));
+                        }
+                    }
+                    None => {
+                        if let Some(event) = decoder.finish() {
+                            self.pending.extend(translator.event(&event));
+                        }
+                        return Ok(self.pending.pop_front());
+                    }
+                },
+                ResponseFormat::Ollama {
+                    line, translator, ..
+                } => match bytes {
+                    Some(bytes) => {
+                        line.extend_from_slice(&bytes);
+                        while let Some(end) = line.iter().position(|&b| b == b'\n') {
+                            let complete: Vec<u8> = line.drain(..=end).collect();
+                            self.pending
+                                .extend(translator.line(&String::from_utf8_lossy(&complete)));
+                        }
+                    }
+                    None => {
+                        let rest = std::mem::take(line);
+                        self.pending
+                            .extend(translator.line(&String::from_utf8_lossy(&rest)));
+                        return Ok(self.pending.pop_front());
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// A response to the client with the status and headers of the upstream response
+pub struct Forwarded<R> {
+    pub status: Status,
+    pub content_type: ContentType,
+    pub headers: Vec<(String, String)>,
+    pub body: R,
+}
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for Forwarded<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'o> {
+        let mut response = Response::build_from(self.body.respond_to(request)?);
+        response.status(self.status).header(self.content_type);
+        for (name, value) in self.headers {
+            response.raw_header(name, value);
+        }
+        response.ok()
+    }
+}
+
+/// A response from the provider that ended up serving a request
+pub struct Upstream {
+    pub provider: ProviderConfig,
+    /// The body as it was sent to `provider`, with its preset applied, before any
+    /// translation to the provider's API
+    pub body: HashMap<String, serde_json::Value>,
+    pub response: UpstreamResponse,
+    /// Id of the log row of the attempt that succeeded
+    pub log_id: i64,
+    pub started: Instant,
+    /// Keeps the request counted against `provider` until the response is consumed
+    pub in_flight: InFlight,
+}
+
+/// Errors worth trying another provider for, as they say nothing about the request itself
+fn should_fail_over(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Whether the retry policy of `provider` covers an answer with `status`
+fn should_retry(provider: &ProviderConfig, status: StatusCode) -> bool {
+    provider
+        .retry
+        .as_ref()
+        .is_some_and(|r| r.retry_on.contains(&status.as_u16()))
+}
+
+/// Reads a `Retry-After` header, given either in seconds or as an HTTP date
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Sends `body` to each provider of `chain` in turn until one of them answers with
+/// something other than a connection error, a 5xx or a 429. Providers with a retry policy
+/// are retried with exponential backoff before moving on. Every attempt is logged as its
+/// own request row. The last provider's response is returned whatever its status.
+///
+/// Nothing has been forwarded to the client at this point, so retrying is always safe.
+pub async fn send_upstream(
+    client: &Client,
+    chain: &[ProviderConfig],
+    kind: RequestKind,
+    body: &HashMap<String, serde_json::Value>,
+) -> Result<Upstream, ApiError> {
+    let mut last_error = ApiError::unavailable("No provider available");
+    for (i, provider) in chain.iter().enumerate() {
+        let is_last_provider = i + 1 == chain.len();
+        let mut body = body.clone();
+        apply_preset(provider, &mut body);
+
+        let stream = body
+            .get("stream")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        if stream {
+            // Force include_usage
+            if let Some(o) = body
+                .entry("stream_options".to_string())
+                .or_insert(serde_json::json!({}))
+                .as_object_mut()
+            {
+                o.insert("include_usage".to_string(), serde_json::json!(true));
+            }
+        }
+
+        let (url, upstream_body) = match upstream_request(provider, kind, &body) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("{}, skipping it", e.message);
+                last_error = e;
+                continue;
+            }
+        };
+
+        let model = body
+            .get("model")
+            .and_then(|v| v.as_str())
+            .unwrap_or(kind.default_model())
+            .to_owned();
+        let max_attempts = provider.retry.as_ref().map_or(1, |r| r.max_attempts.max(1));
+
+        for attempt in 1..=max_attempts {
+            let is_last_attempt = attempt == max_attempts;
+            let log_id = db::insert_request(
+                &provider.id,
+                kind,
+                &serde_json::to_string(&body).unwrap(),
+                &model,
+            )
+            .await;
+
+            let in_flight = InFlight::start(&provider.id);
+            let started = Instant::now();
+            let res = authorize(provider, client.post(&url))
+                .json(&upstream_body)
+                .send()
+                .await;
+
+            let delay = match res {
+                Ok(response) => {
+                    let status = response.status();
+                    let retry = !is_last_attempt && should_retry(provider, status);
+                    let fail_over = !is_last_provider && should_fail_over(status);
+                    if !retry && !fail_over {
+                        return Ok(Upstream {
+                            provider: provider.clone(),
+                            body,
+                            response: UpstreamResponse::new(provider, kind, response),
+                            log_id,
+                            started,
+                            in_flight,
+                        });
+                    }
+
+                    let delay = retry_after(response.headers());
+                    let text = response.text().await.unwrap_or_default();
+                    let logged = error::upstream_error(status.as_u16(), &text);
+                    db::finish_request(
+                        log_id,
+                        Some(status.as_u16()),
+                        None,
+                        &logged.to_string(),
+                        db::Usage::default(),
+                        None,
+                        None,
+                    )
+                    .await;
+                    last_error = ApiError::unavailable(format!(
+                        "Provider '{}' answered with {}",
+                        provider.id, status
+                    ));
+                    if !retry {
+                        warn!(
+                            "Provider '{}' answered with {}, failing over",
+                            provider.id, status
+                        );
+                        break;
+                    }
+                    delay
+                }
+                Err(e) => {
+                    warn!("Request to provider '{}' failed: {}", provider.id, e);
+                    last_error = ApiError::bad_gateway(format!(
+                        "Request to provider '{}' failed: {}",
+                        provider.id, e
+                    ));
+                    db::finish_request(
+                        log_id,
+                        None,
+                        None,
+                        &last_error.to_json().to_string(),
+                        db::Usage::default(),
+                        None,
+                        None,
+                    )
+                    .await;
+                    None
+                }
+            };
+
+            if let (false, Some(policy)) = (is_last_attempt, &provider.retry) {
+                let delay = policy.delay(attempt, delay);
+                warn!(
+                    "Retrying provider '{}' in {:?} (attempt {}/{})",
+                    provider.id,
+                    delay,
+                    attempt + 1,
+                    max_attempts
+                );
+                rocket::tokio::time::sleep(delay).await;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{provider, provider_with};
+
+    #[test]
+    fn fails_over_on_server_errors_and_rate_limits() {
+        for status in [500, 502, 503, 504, 429] {
+            assert!(should_fail_over(StatusCode::from_u16(status).unwrap()));
+        }
+    }
+
+    #[test]
+    fn does_not_fail_over_on_client_errors_or_success() {
+        for status in [200, 400, 401, 404, 422] {
+            assert!(!should_fail_over(StatusCode::from_u16(status).unwrap()));
+        }
+    }
+
+    #[test]
+    fn retries_only_the_statuses_of_the_policy() {
+        let default = provider_with("a", serde_json::json!({ "retry": {} }));
+        for status in [429, 500, 502, 503, 504] {
+            assert!(should_retry(
+                &default,
+                StatusCode::from_u16(status).unwrap()
+            ));
+        }
+        for status in [400, 404, 501] {
+            assert!(!should_retry(
+                &default,
+                StatusCode::from_u16(status).unwrap()
+            ));
+        }
+
+        let custom = provider_with("a", serde_json::json!({ "retry": { "retry_on": [408] } }));
+        assert!(should_retry(&custom, StatusCode::REQUEST_TIMEOUT));
+        assert!(!should_retry(&custom, StatusCode::SERVICE_UNAVAILABLE));
+    }
+
+    #[test]
+    fn never_retries_without_a_policy() {
+        let provider = provider("a");
+        assert!(!should_retry(&provider, StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!should_retry(&provider, StatusCode::TOO_MANY_REQUESTS));
+    }
+
+    fn headers(retry_after: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(reqwest::header::RETRY_AFTER, retry_after.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn reads_retry_after_in_seconds() {
+        assert_eq!(retry_after(&headers("7")), Some(Duration::from_secs(7)));
+        assert_eq!(retry_after(&headers(" 0 ")), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn reads_retry_after_as_http_date() {
+        let later = SystemTime::now() + Duration::from_secs(120);
+        let delay = retry_after(&headers(&httpdate::fmt_http_date(later))).unwrap();
+        assert!(delay > Duration::from_secs(110) && delay <= Duration::from_secs(120));
+
+        let earlier = SystemTime::now() - Duration::from_secs(120);
+        let delay = retry_after(&headers(&httpdate::fmt_http_date(earlier)));
+        assert_eq!(delay, Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn ignores_missing_or_invalid_retry_after() {
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+        assert_eq!(retry_after(&headers("soon")), None);
+        assert_eq!(retry_after(&headers("-1")), None);
+    }
+
+    /// Providers `a` (the active one), `b`, `meta` and `openrouter`, and aliases `smart` and
+    /// `meta/llama`, which looks like a prefixed model
+    fn routing() -> AppConfig {
+        let presets = serde_json::json!({ "presets": [
+            { "id": "fast", "name": "Fast", "overrides": {} },
+            { "id": "cheap", "name": "Cheap", "overrides": {} },
+        ] });
+        serde_json::from_value(serde_json::json!({
+            "providers": [
+                provider_with("a", presets.clone()),
+                provider_with("b", presets),
+                provider("meta"),
+                provider("openrouter"),
+            ],
+            "provider": "a",
+            "db_path": "",
+            "aliases": {
+                "smart": { "provider": "b", "model": "gpt-4o", "preset": "cheap" },
+                "meta/llama": { "provider": "openrouter", "model": "llama-3" },
+            },
+        }))
+        .unwrap()
+    }
+
+    /// The provider id, preset and forwarded model `model` is routed to
+    fn route(
+        config: &AppConfig,
+        provider: Option<&str>,
+        preset: Option<&str>,
+        model: &str,
+    ) -> Result<(String, Option<String>, String), ApiError> {
+        let overrides = ProxyOverrides {
+            provider: provider.map(str::to_owned),
+            preset: preset.map(str::to_owned),
+        };
+        let mut body = HashMap::from([("model".to_string(), serde_json::json!(model))]);
+        let selected = select_provider(config, &overrides, &mut body)?;
+        Ok((
+            selected.id,
+            selected.preset,
+            body["model"].as_str().unwrap().to_owned(),
+        ))
+    }
+
+    fn routed(id: &str, preset: Option<&str>, model: &str) -> (String, Option<String>, String) {
+        (id.to_owned(), preset.map(str::to_owned), model.to_owned())
+    }
+
+    #[test]
+    fn aliases_take_precedence_over_prefixes_and_headers() {
+        let config = routing();
+        let smart = route(&config, Some("a"), None, "smart").unwrap();
+        assert_eq!(smart, routed("b", Some("cheap"), "gpt-4o"));
+        // `meta` is a provider too, but the alias is matched first
+        let llama = route(&config, Some("b"), None, "meta/llama").unwrap();
+        assert_eq!(llama, routed("openrouter", None, "llama-3"));
+    }
+
+    #[test]
+    fn provider_prefixes_take_precedence_over_headers() {
+        let config = routing();
+        let prefixed = route(&config, Some("a"), None, "meta/llama-2").unwrap();
+        assert_eq!(prefixed, routed("meta", None, "llama-2"));
+        // Prefixes that aren't provider ids are part of the model name
+        let unknown = route(&config, Some("b"), None, "google/gemma").unwrap();
+        assert_eq!(unknown, routed("b", None, "google/gemma"));
+    }
+
+    #[test]
+    fn provider_header_takes_precedence_over_the_active_provider() {
+        let mut config = routing();
+        assert_eq!(
+            route(&config, Some("b"), None, "gpt").unwrap(),
+            routed("b", None, "gpt")
+        );
+        assert_eq!(
+            route(&config, None, None, "gpt").unwrap(),
+            routed("a", None, "gpt")
+        );
+
+        let unknown = route(&config, Some("gone"), None, "gpt").unwrap_err();
+        assert_eq!(unknown.code, Some("provider_not_found"));
+        config.provider = None;
+        let inactive = route(&config, None, None, "gpt").unwrap_err();
+        assert_eq!(inactive.status, Status::ServiceUnavailable);
+    }
+
+    #[test]
+    fn preset_header_overrides_the_preset_unless_the_alias_sets_one() {
+        let config = routing();
+        let header = route(&config, Some("b"), Some("fast"), "gpt").unwrap();
+        assert_eq!(header, routed("b", Some("fast"), "gpt"));
+        let prefixed = route(&config, None, Some("fast"), "a/gpt").unwrap();
+        assert_eq!(prefixed, routed("a", Some("fast"), "gpt"));
+        let alias = route(&config, None, Some("fast"), "smart").unwrap();
+        assert_eq!(alias, routed("b", Some("cheap"), "gpt-4o"));
+
+        let unknown = route(&config, None, Some("slow"), "gpt").unwrap_err();
+        assert_eq!(unknown.code, Some("preset_not_found"));
+        // The preset is looked up on the provider the alias resolves to
+        let alias = route(&config, None, Some("fast"), "meta/llama").unwrap_err();
+        assert_eq!(alias.code, Some("preset_not_found"));
+    }
+}
@@ -0,0 +1,150 @@
+/// A server-sent event
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Event {
+    pub event: Option<String>,
+    pub data: String,
+}
+
+/// Incremental decoder for `text/event-stream` bodies. Bytes can be fed in arbitrary
+/// chunks, events are returned once their terminating blank line has been seen.
+#[derive(Default)]
+pub struct Decoder {
+    buffer: Vec<u8>,
+    /// The last line ended in CR, so a LF right after it belongs to the same line ending
+    after_cr: bool,
+    event: Option<String>,
+    data: Vec<String>,
+}
+
+impl Decoder {
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<Event> {
+        self.buffer.extend_from_slice(bytes);
+        let mut events = Vec::new();
+        // Lines end in LF, CRLF or CR
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n' || b == b'\r') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let crlf = self.after_cr && line == b"\n";
+            self.after_cr = line[end] == b'\r';
+            if crlf {
+                continue;
+            }
+            let line = String::from_utf8_lossy(&line[..end]);
+            if let Some(event) = self.line(&line) {
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    /// Returns the last event if the stream ended without a trailing blank line
+    pub fn finish(&mut self) -> Option<Event> {
+        let rest = std::mem::take(&mut self.buffer);
+        let rest = String::from_utf8_lossy(&rest);
+        let rest = rest.trim_end_matches(['\n', '\r']);
+        if !rest.is_empty() {
+            self.line(rest);
+        }
+        self.dispatch()
+    }
+
+    fn line(&mut self, line: &str) -> Option<Event> {
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        if line.starts_with(':') {
+            return None;
+        }
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match field {
+            "event" => self.event = Some(value.to_owned()),
+            "data" => self.data.push(value.to_owned()),
+            _ => {}
+        }
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<Event> {
+        if self.data.is_empty() && self.event.is_none() {
+            return None;
+        }
+        Some(Event {
+            event: self.event.take(),
+            data: std::mem::take(&mut self.data).join("\n"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(data: &str) -> Event {
+        Event {
+            event: None,
+            data: data.to_owned(),
+        }
+    }
+
+    #[test]
+    fn decodes_events_split_across_chunks() {
+        let mut decoder = Decoder::default();
+        assert!(decoder.feed(b"da").is_empty());
+        assert!(decoder.feed(b"ta: {\"a\":").is_empty());
+        assert!(decoder.feed(b"1}\n").is_empty());
+        assert_eq!(decoder.feed(b"\n"), [data("{\"a\":1}")]);
+    }
+
+    #[test]
+    fn decodes_events_batched_in_one_chunk() {
+        let mut decoder = Decoder::default();
+        assert_eq!(
+            decoder.feed(b"data: 1\n\ndata: 2\n\ndata: [DONE]\n\n"),
+            [data("1"), data("2"), data("[DONE]")]
+        );
+    }
+
+    #[test]
+    fn decodes_crlf_and_cr_line_endings() {
+        let mut decoder = Decoder::default();
+        assert_eq!(decoder.feed(b"data: 1\r\n\r\n"), [data("1")]);
+        assert_eq!(
+            decoder.feed(b"data: 2\r\rdata: 3\r\r"),
+            [data("2"), data("3")]
+        );
+        // A CRLF split between chunks is one line ending, not two
+        assert!(decoder.feed(b"data: 4\r").is_empty());
+        assert!(decoder.feed(b"\ndata: 5\r").is_empty());
+        assert_eq!(decoder.feed(b"\n\r\n"), [data("4\n5")]);
+    }
+
+    #[test]
+    fn skips_comments_and_unknown_fields() {
+        let mut decoder = Decoder::default();
+        assert!(decoder.feed(b": keep-alive\n\n").is_empty());
+        assert_eq!(
+            decoder.feed(b": comment\nid: 7\nretry: 1000\ndata: 1\n\n"),
+            [data("1")]
+        );
+    }
+
+    #[test]
+    fn decodes_event_names_and_multi_line_data() {
+        let mut decoder = Decoder::default();
+        assert_eq!(
+            decoder.feed(b"event: message_start\ndata: first\ndata:second\ndata\n\n"),
+            [Event {
+                event: Some("message_start".to_string()),
+                data: "first\nsecond\n".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn finish_returns_an_unterminated_event() {
+        let mut decoder = Decoder::default();
+        assert!(decoder.feed(b"data: 1\ndata: 2").is_empty());
+        assert_eq!(decoder.finish(), Some(data("1\n2")));
+        assert_eq!(decoder.finish(), None);
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection};
+use serde_json::json;
+
+/// What the requests are grouped by
+#[derive(Clone, Copy)]
+pub enum Grouping {
+    Provider,
+    Model,
+    /// The UTC day the request was made, as `YYYY-MM-DD`
+    Day,
+    /// The UTC hour the request was made, as `YYYY-MM-DD HH:00`
+    Hour,
+}
+
+impl Grouping {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "provider" => Some(Grouping::Provider),
+            "model" => Some(Grouping::Model),
+            "day" => Some(Grouping::Day),
+            "hour" => Some(Grouping::Hour),
+            _ => None,
+        }
+    }
+
+    fn key(self) -> &'static str {
+        match self {
+            Grouping::Provider => "provider_id",
+            Grouping::Model => "model",
+            Grouping::Day => "date(request_time)",
+            Grouping::Hour => "strftime('%Y-%m-%d %H:00', request_time)",
+        }
+    }
+}
+
+/// Milliseconds between the request and the response. The times are stored with a
+/// precision of one second.
+const LATENCY: &str = "ROUND((julianday(response_time) - julianday(request_time)) * 86400000)";
+
+/// Aggregates the requests matching `filter`, a WHERE clause with the `params` for its
+/// placeholders, per group. Only finished requests count towards the error rate,
+/// cancelled ones don't count as errors.
+pub fn aggregate(
+    conn: &Connection,
+    grouping: Grouping,
+    filter: &str,
+    params: &[Value],
+) -> rusqlite::Result<Vec<serde_json::Value>> {
+    let key = grouping.key();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {key},
+            COUNT(*),
+            COUNT(outcome),
+            COUNT(CASE WHEN outcome IN ('upstream_error', 'network_error') THEN 1 END),
+            COUNT(CASE WHEN outcome = 'cancelled' THEN 1 END),
+            SUM(prompt_tokens),
+            SUM(completion_tokens),
+            AVG(speed),
+            AVG({LATENCY}),
+            SUM(cached_tokens),
+            SUM(cost)
+        FROM requests {filter} GROUP BY 1 ORDER BY 1",
+    ))?;
+    let mut groups = stmt
+        .query_map(params_from_iter(params), |row| {
+            let requests: i64 = row.get(1)?;
+            let finished: i64 = row.get(2)?;
+            let errors: i64 = row.get(3)?;
+            Ok((
+                row.get::<_, Option<String>>(0)?,
+                json!({
+                    "requests": requests,
+                    "finished": finished,
+                    "errors": errors,
+                    "cancelled": row.get::<_, i64>(4)?,
+                    "error_rate": (finished > 0).then(|| errors as f64 / finished as f64),
+                    "prompt_tokens": row.get::<_, Option<i64>>(5)?.unwrap_or(0),
+                    "completion_tokens": row.get::<_, Option<i64>>(6)?.unwrap_or(0),
+                    "cached_tokens": row.get::<_, Option<i64>>(9)?.unwrap_or(0),
+                    "cost": row.get::<_, Option<f64>>(10)?.unwrap_or(0.0),
+                    "speed": { "avg": row.get::<_, Option<f64>>(7)? },
+                    "latency_ms": { "avg": row.get::<_, Option<f64>>(8)? },
+                }),
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    for (column, field) in [("speed", "speed"), (LATENCY, "latency_ms")] {
+        let values = sorted_values(conn, key, column, filter, params)?;
+        for (group, stats) in &mut groups {
+            let values = values.get(group).map_or(&[][..], |v| v);
+            for (name, p) in [("p50", 50.0), ("p90", 90.0), ("p99", 99.0)] {
+                stats[field][name] = json!(percentile(values, p));
+            }
+        }
+    }
+
+    Ok(groups
+        .into_iter()
+        .map(|(group, mut stats)| {
+            stats["key"] = json!(group);
+            stats
+        })
+        .collect())
+}
+
+/// The non-NULL values of `column` per group, sorted ascending
+fn sorted_values(
+    conn: &Connection,
+    key: &str,
+    column: &str,
+    filter: &str,
+    params: &[Value],
+) -> rusqlite::Result<HashMap<Option<String>, Vec<f64>>> {
+    let condition = format!("{column} IS NOT NULL");
+    let filter = if filter.is_empty() {
+        format!("WHERE {condition}")
+    } else {
+        format!("{filter} AND {condition}")
+    };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {key}, {column} FROM requests {filter} ORDER BY 1, 2"
+    ))?;
+    let mut rows = stmt.query(params_from_iter(params))?;
+    let mut groups: HashMap<Option<String>, Vec<f64>> = HashMap::new();
+    while let Some(row) = rows.next()? {
+        groups.entry(row.get(0)?).or_default().push(row.get(1)?);
+    }
+    Ok(groups)
+}
+
+/// The nearest-rank percentile of sorted values
+fn percentile(sorted: &[f64], p: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE requests (
+                provider_id TEXT NOT NULL,
+                model TEXT NOT NULL,
+                request_time TIMESTAMP NOT NULL,
+                response_time TIMESTAMP,
+                prompt_tokens INTEGER,
+                completion_tokens INTEGER,
+                cached_tokens INTEGER,
+                speed INTEGER,
+                outcome TEXT,
+                cost REAL
+            );
+            INSERT INTO requests VALUES
+                ('a', 'm1', '2024-05-01 10:15:00', '2024-05-01 10:15:01', 10, 5, 0, 20, 'success', 0.5),
+                ('a', 'm2', '2024-05-01 10:45:00', '2024-05-01 10:45:03', 10, 5, 2, 40, 'success', 0.25),
+                ('a', 'm1', '2024-05-01 11:00:00', '2024-05-01 11:00:02', 10, NULL, NULL, NULL, 'upstream_error', NULL),
+                ('b', 'm1', '2024-05-02 09:00:00', NULL, NULL, NULL, NULL, NULL, NULL, NULL),
+                ('b', 'm1', '2024-05-02 09:30:00', '2024-05-02 09:30:01', NULL, NULL, NULL, NULL, 'cancelled', NULL)",
+        )
+        .unwrap();
+        conn
+    }
+
+    fn keys(groups: &[serde_json::Value]) -> Vec<&str> {
+        groups.iter().filter_map(|g| g["key"].as_str()).collect()
+    }
+
+    #[test]
+    fn percentile_of_edge_cases() {
+        assert_eq!(percentile(&[], 50.0), None);
+        assert_eq!(percentile(&[7.0], 0.0), Some(7.0));
+        assert_eq!(percentile(&[7.0], 99.0), Some(7.0));
+        let values: Vec<f64> = (1..=10).map(f64::from).collect();
+        assert_eq!(percentile(&values, 50.0), Some(5.0));
+        assert_eq!(percentile(&values, 90.0), Some(9.0));
+        assert_eq!(percentile(&values, 99.0), Some(10.0));
+        assert_eq!(percentile(&values, 100.0), Some(10.0));
+    }
+
+    #[test]
+    fn groups_by_provider() {
+        let groups = aggregate(&database(), Grouping::Provider, "", &[]).unwrap();
+        assert_eq!(keys(&groups), ["a", "b"]);
+        let a = &groups[0];
+        assert_eq!(a["requests"], 3);
+        assert_eq!(a["errors"], 1);
+        assert_eq!(a["error_rate"], 1.0 / 3.0);
+        assert_eq!(a["prompt_tokens"], 30);
+        assert_eq!(a["cached_tokens"], 2);
+        assert_eq!(a["cost"], 0.75);
+        assert_eq!(
+            a["speed"],
+            json!({ "avg": 30.0, "p50": 20.0, "p90": 40.0, "p99": 40.0 })
+        );
+        assert_eq!(a["latency_ms"]["p50"], 2000.0);
+        let b = &groups[1];
+        assert_eq!(b["finished"], 1);
+        assert_eq!(b["cancelled"], 1);
+        assert_eq!(b["error_rate"], 0.0);
+        assert_eq!(b["speed"]["p50"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn groups_by_model() {
+        let groups = aggregate(&database(), Grouping::Model, "", &[]).unwrap();
+        assert_eq!(keys(&groups), ["m1", "m2"]);
+        assert_eq!(groups[0]["requests"], 4);
+        assert_eq!(groups[1]["speed"]["p99"], 40.0);
+    }
+
+    #[test]
+    fn groups_by_day_and_hour() {
+        let conn = database();
+        let days = aggregate(&conn, Grouping::Day, "", &[]).unwrap();
+        assert_eq!(keys(&days), ["2024-05-01", "2024-05-02"]);
+        assert_eq!(days[0]["requests"], 3);
+        let hours = aggregate(&conn, Grouping::Hour, "", &[]).unwrap();
+        assert_eq!(
+            keys(&hours),
+            ["2024-05-01 10:00", "2024-05-01 11:00", "2024-05-02 09:00"]
+        );
+        assert_eq!(hours[0]["latency_ms"]["p90"], 3000.0);
+    }
+
+    #[test]
+    fn applies_the_filter() {
+        let groups = aggregate(
+            &database(),
+            Grouping::Provider,
+            "WHERE model = ?",
+            &[Value::from("m2".to_string())],
+        )
+        .unwrap();
+        assert_eq!(keys(&groups), ["a"]);
+        assert_eq!(groups[0]["requests"], 1);
+        assert_eq!(groups[0]["speed"]["p50"], 40.0);
+    }
+}
@@ -0,0 +1,42 @@
+//! Fixtures shared by the unit tests of several modules
+
+use serde_json::{json, Value};
+
+use crate::config::ProviderConfig;
+use crate::sse;
+
+/// A provider named after its id, without presets
+pub fn provider(id: &str) -> ProviderConfig {
+    provider_with(id, json!({}))
+}
+
+/// A provider named after its id, with the extra config `fields`
+pub fn provider_with(id: &str, fields: Value) -> ProviderConfig {
+    let mut provider = json!({ "name": id, "id": id, "presets": [] });
+    for (key, value) in fields.as_object().into_iter().flatten() {
+        provider[key] = value.clone();
+    }
+    serde_json::from_value(provider).unwrap()
+}
+
+/// The ids of a chain of providers, in order
+pub fn ids(chain: &[ProviderConfig]) -> Vec<&str> {
+    chain.iter().map(|p| p.id.as_str()).collect()
+}
+
+pub fn sse(event: Option<&str>, data: Value) -> sse::Event {
+    sse::Event {
+        event: event.map(str::to_owned),
+        data: data.to_string(),
+    }
+}
+
+/// The JSON payloads of SSE frames, `[DONE]` left out
+pub fn payloads(frames: &[String]) -> Vec<Value> {
+    frames
+        .iter()
+        .flat_map(|frame| frame.lines())
+        .filter_map(|line| line.strip_prefix("data: "))
+        .filter_map(|data| serde_json::from_str(data).ok())
+        .collect()
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::warn;
+use tiktoken_rs::CoreBPE;
+
+use crate::config::LocalTokenizer;
+
+/// Where a token count in the request log comes from
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TokenMethod {
+    /// Reported by the upstream in `usage`
+    Usage,
+    /// The prompt was sent as tokens
+    Request,
+    /// The upstream's `/tokenize` endpoint
+    Upstream,
+    /// A tokenizer configured in `tokenizers`
+    Local,
+}
+
+impl TokenMethod {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TokenMethod::Usage => "usage",
+            TokenMethod::Request => "request",
+            TokenMethod::Upstream => "upstream",
+            TokenMethod::Local => "local",
+        }
+    }
+}
+
+enum Loaded {
+    HuggingFace(Box<tokenizers::Tokenizer>),
+    Tiktoken(CoreBPE),
+}
+
+/// Tokenizers already read from disk, files are only loaded once until the config is
+/// reloaded
+static LOADED: LazyLock<Mutex<HashMap<String, Arc<Loaded>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Split pattern of the cl100k_base encoding, used for tiktoken files without a pattern
+const CL100K_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+fn load(tokenizer: &LocalTokenizer) -> Result<Arc<Loaded>, String> {
+    let key = serde_json::to_string(tokenizer).unwrap();
+    if let Some(loaded) = LOADED.lock().unwrap().get(&key) {
+        return Ok(loaded.clone());
+    }
+    let loaded = match tokenizer {
+        LocalTokenizer::HuggingFace { path } => Loaded::HuggingFace(Box::new(
+            tokenizers::Tokenizer::from_file(path).map_err(|e| e.to_string())?,
+        )),
+        LocalTokenizer::Tiktoken { path, pattern } => {
+            let ranks = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+            let encoder = ranks
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(|line| {
+                    let (token, rank) = line
+                        .split_once(' ')
+                        .ok_or_else(|| format!("invalid line '{}'", line))?;
+                    let token = STANDARD.decode(token).map_err(|e| e.to_string())?;
+                    let rank = rank
+                        .parse()
+                        .map_err(|_| format!("invalid rank '{}'", rank))?;
+                    Ok((token, rank))
+                })
+                .collect::<Result<_, String>>()?;
+            let pattern = pattern.as_deref().unwrap_or(CL100K_PATTERN);
+            Loaded::Tiktoken(
+                CoreBPE::new(encoder, Default::default(), pattern).map_err(|e| e.to_string())?,
+            )
+        }
+    };
+    let loaded = Arc::new(loaded);
+    LOADED.lock().unwrap().insert(key, loaded.clone());
+    Ok(loaded)
+}
+
+/// Drops the tokenizers read from disk, so files replaced since are read again
+pub fn forget_loaded() {
+    LOADED.lock().unwrap().clear();
+}
+
+/// Runs `f` with a local tokenizer. Loading and `f` run on the blocking thread pool, the
+/// files can be large.
+async fn run<T: Send + 'static>(
+    tokenizer: &LocalTokenizer,
+    f: impl FnOnce(&Loaded) -> Result<T, String> + Send + 'static,
+) -> Option<T> {
+    let tokenizer = tokenizer.clone();
+    rocket::tokio::task::spawn_blocking(move || f(&*load(&tokenizer)?))
+        .await
+        .unwrap()
+        .inspect_err(|e| warn!("Local tokenizer failed: {}", e))
+        .ok()
+}
+
+/// Tokenizes `text` with a local tokenizer
+pub async fn encode(tokenizer: &LocalTokenizer, text: &str) -> Option<Vec<u64>> {
+    let text = text.to_owned();
+    run(tokenizer, move |loaded| match loaded {
+        Loaded::HuggingFace(t) => t
+            .encode(text, false)
+            .map(|e| e.get_ids().iter().map(|&id| id as u64).collect())
+            .map_err(|e| e.to_string()),
+        Loaded::Tiktoken(bpe) => Ok(bpe
+            .encode_ordinary(&text)
+            .into_iter()
+            .map(|id| id as u64)
+            .collect()),
+    })
+    .await
+}
+
+/// Turns tokens back into text with a local tokenizer. `None` if the tokenizer fails or
+/// doesn't know one of the tokens.
+pub async fn decode(tokenizer: &LocalTokenizer, tokens: &[u64]) -> Option<String> {
+    let tokens = tokens
+        .iter()
+        .map(|&t| u32::try_from(t).map_err(|_| format!("invalid token {}", t)))
+        .collect::<Result<Vec<u32>, String>>();
+    run(tokenizer, move |loaded| match loaded {
+        Loaded::HuggingFace(t) => t.decode(&tokens?, false).map_err(|e| e.to_string()),
+        Loaded::Tiktoken(bpe) => bpe.decode(tokens?).map_err(|e| e.to_string()),
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Writes a `.tiktoken` file of every byte and the `merges` ranked after them
+    fn tiktoken_file(name: &str, merges: &[&str]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("aiswitch-{}-{}.tiktoken", name, std::process::id()));
+        let tokens = (0..=255u8).map(|b| vec![b]);
+        let tokens = tokens.chain(merges.iter().map(|m| m.as_bytes().to_vec()));
+        let ranks: String = tokens
+            .enumerate()
+            .map(|(rank, token)| format!("{} {}\n", STANDARD.encode(token), rank))
+            .collect();
+        std::fs::write(&path, ranks).unwrap();
+        path
+    }
+
+    #[rocket::async_test]
+    async fn tiktoken_splits_with_cl100k_unless_configured() {
+        let path = tiktoken_file("pattern", &["n'"]);
+        let default = LocalTokenizer::Tiktoken {
+            path: path.clone(),
+            pattern: None,
+        };
+        // cl100k_base splits off the contraction, so `n'` can't be merged
+        let tokens = encode(&default, "don't").await.unwrap();
+        assert_eq!(tokens, [100, 111, 110, 39, 116]);
+        assert_eq!(decode(&default, &tokens).await.unwrap(), "don't");
+
+        let whole = LocalTokenizer::Tiktoken {
+            path: path.clone(),
+            pattern: Some(".+".to_string()),
+        };
+        let tokens = encode(&whole, "don't").await.unwrap();
+        assert_eq!(tokens, [100, 111, 256, 116]);
+        assert_eq!(decode(&whole, &tokens).await.unwrap(), "don't");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn replaced_files_are_read_again_once_forgotten() {
+        let path = tiktoken_file("reload", &[]);
+        let tokenizer = LocalTokenizer::Tiktoken {
+            path: path.clone(),
+            pattern: None,
+        };
+        assert_eq!(encode(&tokenizer, "hi").await.unwrap(), [104, 105]);
+        tiktoken_file("reload", &["hi"]);
+        forget_loaded();
+        assert_eq!(encode(&tokenizer, "hi").await.unwrap(), [256]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn unreadable_tokenizers_count_nothing() {
+        let missing = LocalTokenizer::Tiktoken {
+            path: std::env::temp_dir().join("aiswitch-missing.tiktoken"),
+            pattern: None,
+        };
+        assert_eq!(encode(&missing, "hi").await, None);
+        let path = tiktoken_file("unknown-token", &[]);
+        let tokenizer = LocalTokenizer::Tiktoken {
+            path: path.clone(),
+            pattern: None,
+        };
+        assert_eq!(decode(&tokenizer, &[1 << 40]).await, None);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -6,6 +6,8 @@ import {
   ChatResponseChunk,
   CompletionRequest,
   CompletionResponse,
+  EmbeddingRequest,
+  EmbeddingResponse,
   useGetLogQuery,
 } from "./api";
 import { Chat } from "./components/chat";
@@ -29,7 +31,25 @@ export default function Review() {
   const streamingResponse = request.stream ?? false;
   const response = data!.response;
 
-  if (chat) {
+  if (data!.request_type === "embedding") {
+    const input = (request as EmbeddingRequest).input;
+    messages = (Array.isArray(input) ? input : [input]).map((content) => ({
+      role: "user",
+      content,
+    }));
+
+    if (response) {
+      const embeddings = (response as EmbeddingResponse).data ?? [];
+      const dimensions = embeddings[0]?.dimensions;
+      messages = [
+        ...messages,
+        {
+          role: "assistant",
+          content: `${embeddings.length} embedding(s)${dimensions ? ` of ${dimensions} dimensions` : ""}`,
+        },
+      ];
+    }
+  } else if (chat) {
     messages = (request as ChatRequest).messages;
 
     if (response) {
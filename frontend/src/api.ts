@@ -10,6 +10,43 @@ export interface LogOverview {
   response_tokens?: number;
   response_time: string;
   chat: boolean;
+  request_type: "chat" | "completion" | "embedding";
+  outcome?: LogOutcome;
+  error?: string;
+  status?: number;
+  cached_tokens?: number;
+  /** In US dollars, missing when the model has no price */
+  cost?: number;
+  prompt_tokens_method?: TokenMethod;
+  completion_tokens_method?: TokenMethod;
+  /** Non-text parts of a chat prompt, only known for chat requests */
+  image_parts?: number;
+  audio_parts?: number;
+  file_parts?: number;
+}
+
+/** Where a token count comes from, missing for requests logged before it was recorded */
+export type TokenMethod = "usage" | "request" | "upstream" | "local";
+
+export type LogOutcome =
+  | "success"
+  | "upstream_error"
+  | "network_error"
+  | "cancelled";
+
+/** Narrows down the listed logs, see `LogFilter` in the backend */
+export interface LogFilter {
+  provider_id?: string;
+  model?: string;
+  type?: LogOverview["request_type"];
+  outcome?: LogOutcome | "pending";
+  from?: string;
+  to?: string;
+  min_tokens?: number;
+  max_tokens?: number;
+  min_speed?: number;
+  max_speed?: number;
+  search?: string;
 }
 
 export interface ChatMessage {
@@ -29,6 +66,15 @@ export interface ChatRequest {
   stream: boolean;
 }
 
+export interface EmbeddingRequest {
+  model: string;
+  input: string | string[];
+}
+
+export interface EmbeddingResponse {
+  data: { index: number; dimensions?: number }[];
+}
+
 export interface ChatResponse {
   id: string;
   created: number;
@@ -48,12 +94,13 @@ export interface CompletionResponse {
 }
 
 interface LogEntry extends LogOverview {
-  request: ChatRequest | CompletionRequest;
+  request: ChatRequest | CompletionRequest | EmbeddingRequest;
   response?:
     | ChatResponse
     | ChatResponseChunk[]
     | CompletionResponse
-    | CompletionResponse[];
+    | CompletionResponse[]
+    | EmbeddingResponse;
 }
 
 export const api = createApi({
@@ -141,9 +188,10 @@ export const api = createApi({
           by: string;
           desc: boolean;
         };
+        filter?: LogFilter;
       }
     >({
-      query: ({ pageIndex, pageSize, sorting }) => ({
+      query: ({ pageIndex, pageSize, sorting, filter }) => ({
         url: `logs`,
         params: {
           page: pageIndex,
@@ -151,6 +199,7 @@ export const api = createApi({
           sort: sorting
             ? `${sorting.by},${sorting.desc ? "desc" : "asc"}`
             : undefined,
+          ...filter,
         },
       }),
     }),
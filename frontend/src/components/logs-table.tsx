@@ -17,6 +17,7 @@ import {
 } from "./ui/table";
 import { Button } from "./ui/button";
 import {
+  Binary,
   MessageCircle,
   MessageSquareText,
   ChevronDown,
@@ -34,7 +35,13 @@ import {
   SelectTrigger,
   SelectValue,
 } from "./ui/select";
-import { LogOverview, useGetLogsQuery } from "@/api";
+import {
+  LogFilter,
+  LogOutcome,
+  LogOverview,
+  useGetLogsQuery,
+} from "@/api";
+import { Input } from "./ui/input";
 import {
   DropdownMenu,
   DropdownMenuContent,
@@ -43,8 +50,23 @@ import {
 } from "./ui/dropdown-menu";
 import { Link } from "react-router";
 
+const typeTitles: Record<LogOverview["request_type"], string> = {
+  chat: "Chat",
+  completion: "Completion",
+  embedding: "Embedding",
+};
+
+const outcomeTitles: Record<LogOutcome | "pending", string> = {
+  success: "Success",
+  upstream_error: "Upstream error",
+  network_error: "Network error",
+  cancelled: "Cancelled",
+  pending: "Pending",
+};
+
 export const LogsTable = () => {
   const [sorting, setSorting] = useState<SortingState>([]);
+  const [filter, setFilter] = useState<LogFilter>({});
 
   const columns: ColumnDef<LogOverview>[] = [
     {
@@ -62,9 +84,15 @@ export const LogsTable = () => {
           <Link to={`/review/${row.original.id}`}>
             <div
               className="flex"
-              title={row.original.chat ? "Chat" : "Completion"}
+              title={typeTitles[row.original.request_type]}
             >
-              {row.original.chat ? <MessageCircle /> : <MessageSquareText />}
+              {row.original.request_type === "chat" ? (
+                <MessageCircle />
+              ) : row.original.request_type === "embedding" ? (
+                <Binary />
+              ) : (
+                <MessageSquareText />
+              )}
             </div>
           </Link>
         );
@@ -74,6 +102,19 @@ export const LogsTable = () => {
       header: "Model",
       accessorKey: "model",
     },
+    {
+      header: "Status",
+      accessorKey: "status",
+    },
+    {
+      header: "Outcome",
+      accessorKey: "outcome",
+      cell: ({ row }) => (
+        <span title={row.original.error}>
+          {outcomeTitles[row.original.outcome ?? "pending"]}
+        </span>
+      ),
+    },
     {
       header: "Prompt Tokens",
       accessorKey: "prompt_tokens",
@@ -94,6 +135,14 @@ export const LogsTable = () => {
       header: "Speed",
       accessorKey: "speed",
     },
+    {
+      header: "Cost",
+      accessorKey: "cost",
+      cell: ({ row }) =>
+        row.original.cost !== undefined
+          ? `$${row.original.cost.toFixed(6)}`
+          : null,
+    },
     {
       id: "actions",
       cell: ({ row }) => {
@@ -131,6 +180,7 @@ export const LogsTable = () => {
             desc: sorting[0].desc,
           }
         : undefined,
+      filter,
     },
     {
       refetchOnFocus: true,
@@ -156,8 +206,69 @@ export const LogsTable = () => {
     debugTable: true,
   });
 
+  const updateFilter = (update: Partial<LogFilter>) => {
+    setFilter({ ...filter, ...update });
+    table.firstPage();
+  };
+
   return (
     <div className="rounded-md border">
+      <div className="flex items-center gap-2 p-2">
+        <Input
+          placeholder="Search requests and responses..."
+          defaultValue={filter.search}
+          onKeyDown={(e) => {
+            if (e.key === "Enter") {
+              updateFilter({ search: e.currentTarget.value || undefined });
+            }
+          }}
+          className="max-w-sm"
+        />
+        <Select
+          value={filter.type ?? "all"}
+          onValueChange={(value) =>
+            updateFilter({
+              type:
+                value === "all"
+                  ? undefined
+                  : (value as LogOverview["request_type"]),
+            })
+          }
+        >
+          <SelectTrigger className="w-[180px]">
+            <SelectValue />
+          </SelectTrigger>
+          <SelectContent>
+            <SelectItem value="all">All types</SelectItem>
+            {Object.entries(typeTitles).map(([value, title]) => (
+              <SelectItem key={value} value={value}>
+                {title}
+              </SelectItem>
+            ))}
+          </SelectContent>
+        </Select>
+        <Select
+          value={filter.outcome ?? "all"}
+          onValueChange={(value) =>
+            updateFilter({
+              outcome:
+                value === "all" ? undefined : (value as LogOutcome | "pending"),
+            })
+          }
+        >
+          <SelectTrigger className="w-[180px]">
+            <SelectValue />
+          </SelectTrigger>
+          <SelectContent>
+            <SelectItem value="all">All outcomes</SelectItem>
+            {Object.entries(outcomeTitles).map(([value, title]) => (
+              <SelectItem key={value} value={value}>
+                {title}
+              </SelectItem>
+            ))}
+          </SelectContent>
+        </Select>
+      </div>
       <Table>
         <TableHeader>
           {table.getHeaderGroups().map((headerGroup) => (